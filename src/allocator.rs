@@ -1,45 +1,269 @@
 // heap allocator initialization and types
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use crate::memory::{self, huge::{self, PageSizeKind}, vma::{self, VmaKind}};
 pub mod bump;
 pub mod linked_list;
 pub mod fixed_size_block;
-use fixed_size_block::FixedSizeBlockAllocator;
+pub mod slab;
+pub mod guarded;
+use bump::BumpAllocator;
+use linked_list::{FitStrategy, LinkedListAllocator};
+use fixed_size_block::{FixedSizeBlockAllocator, BLOCK_SIZES};
+use guarded::GuardedAllocator;
+#[cfg(any(
+    all(feature = "heap-bump", feature = "heap-linked-list"),
+    all(feature = "heap-bump", feature = "heap-guarded"),
+    all(feature = "heap-linked-list", feature = "heap-guarded"),
+))]
+compile_error!("the heap-* features pick the heap backend, enable at most one of them");
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; 
+pub const HEAP_SIZE: usize = 100 * 1024;
+// virtual window reserved for the heap, growth never maps past it
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;
+// smallest amount the heap grows by, to keep remapping rare
+pub const HEAP_GROWTH_STEP: usize = 64 * 1024;
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HeapBackend {
+    Bump,
+    LinkedList,
+    FixedSizeBlock,
+    // debug mode, one guard page per allocation
+    Guarded,
+}
+impl HeapBackend {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HeapBackend::Bump => "bump",
+            HeapBackend::LinkedList => "linked-list",
+            HeapBackend::FixedSizeBlock => "fixed-size-block",
+            HeapBackend::Guarded => "guarded",
+        }
+    }
+    // the backend picked by the heap-* cargo feature, fixed-size-block
+    // unless one is enabled; at most one can be
+    pub const fn configured() -> Self {
+        if cfg!(feature = "heap-guarded") {
+            HeapBackend::Guarded
+        } else if cfg!(feature = "heap-linked-list") {
+            HeapBackend::LinkedList
+        } else if cfg!(feature = "heap-bump") {
+            HeapBackend::Bump
+        } else {
+            HeapBackend::FixedSizeBlock
+        }
+    }
+    fn from_u8(raw: u8) -> Self {
+        match raw {
+            0 => HeapBackend::Bump,
+            1 => HeapBackend::LinkedList,
+            3 => HeapBackend::Guarded,
+            _ => HeapBackend::FixedSizeBlock,
+        }
+    }
+}
+// global allocator that forwards to the backend picked at boot
+pub struct KernelAllocator {
+    backend: AtomicU8,
+    bump: Locked<BumpAllocator>,
+    linked_list: Locked<LinkedListAllocator>,
+    fixed_size_block: Locked<FixedSizeBlockAllocator>,
+    guarded: Locked<GuardedAllocator>,
+}
+impl KernelAllocator {
+    const fn new() -> Self {
+        KernelAllocator {
+            backend: AtomicU8::new(HeapBackend::configured() as u8),
+            bump: Locked::new(BumpAllocator::new()),
+            linked_list: Locked::new(LinkedListAllocator::new()),
+            fixed_size_block: Locked::new(FixedSizeBlockAllocator::new()),
+            guarded: Locked::new(GuardedAllocator::new()),
+        }
+    }
+    fn backend(&self) -> HeapBackend {
+        HeapBackend::from_u8(self.backend.load(Ordering::Relaxed))
+    }
+}
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.backend() {
+            HeapBackend::Bump => self.bump.alloc(layout),
+            HeapBackend::LinkedList => self.linked_list.alloc(layout),
+            HeapBackend::FixedSizeBlock => self.fixed_size_block.alloc(layout),
+            HeapBackend::Guarded => self.guarded.alloc(layout),
+        }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match self.backend() {
+            HeapBackend::Bump => self.bump.dealloc(ptr, layout),
+            HeapBackend::LinkedList => self.linked_list.dealloc(ptr, layout),
+            HeapBackend::FixedSizeBlock => self.fixed_size_block.dealloc(ptr, layout),
+            HeapBackend::Guarded => self.guarded.dealloc(ptr, layout),
+        }
+    }
+}
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(
-    FixedSizeBlockAllocator::new());
-// initializes the heap memory allocator
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        };
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+static HEAP_INITIALIZED: AtomicBool = AtomicBool::new(false);
+// initializes the heap memory allocator with the backend the kernel was
+// built with, see `HeapBackend::configured`
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    init_heap_with(HeapBackend::configured())
+}
+// initializes the heap memory allocator with the given backend,
+// the backend can't be changed once the heap is up
+pub fn init_heap_with(backend: HeapBackend) -> Result<(), MapToError<Size4KiB>> {
+    assert!(
+        !HEAP_INITIALIZED.swap(true, Ordering::SeqCst),
+        "heap already initialized"
+    );
+    memory::with_mapper(|mapper| {
+        // the whole reserved window must be free so growth can't clobber anything
+        let reserved = page_range(HEAP_START, HEAP_MAX_SIZE);
+        if let Some(frame) = reserved.filter_map(|page| mapper.translate_page(page).ok()).next() {
+            return Err(MapToError::PageAlreadyMapped(frame));
+        }
+        map_heap_pages(mapper, HEAP_START, HEAP_SIZE)
+    })?;
+    reserve_window("heap", HEAP_START, HEAP_MAX_SIZE);
+    if backend == HeapBackend::Guarded {
+        reserve_window("guarded heap", guarded::GUARDED_HEAP_START, guarded::GUARDED_HEAP_SIZE);
     }
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        match backend {
+            HeapBackend::Bump => {
+                let mut bump = ALLOCATOR.bump.lock();
+                bump.init(HEAP_START, HEAP_SIZE);
+                bump.set_growth(grow_heap);
+            }
+            HeapBackend::LinkedList => {
+                let mut linked_list = ALLOCATOR.linked_list.lock();
+                linked_list.init(HEAP_START, HEAP_SIZE);
+                linked_list.set_growth(grow_heap);
+            }
+            HeapBackend::FixedSizeBlock => {
+                let mut fixed_size_block = ALLOCATOR.fixed_size_block.lock();
+                fixed_size_block.init(HEAP_START, HEAP_SIZE);
+                fixed_size_block.set_growth(grow_heap);
+            }
+            // maps its own pages per allocation, the heap window stays unused
+            HeapBackend::Guarded => {}
+        }
     }
+    ALLOCATOR.backend.store(backend as u8, Ordering::Relaxed);
     Ok(())
 }
+// records a heap window in the kernel areas, the heap maps its pages itself
+fn reserve_window(name: &'static str, start: usize, size: usize) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = VirtAddr::new(start as u64);
+    vma::map_at(name, start, size as u64, flags, VmaKind::Reserved)
+        .expect("heap window overlaps another kernel area");
+}
+// picks first-fit or best-fit for the linked-list backend
+pub fn set_fit_strategy(strategy: FitStrategy) {
+    ALLOCATOR.linked_list.lock().set_strategy(strategy);
+}
+// returns the backend serving the global allocator
+pub fn backend() -> HeapBackend {
+    ALLOCATOR.backend()
+}
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    pub free_blocks: usize,
+    pub live_allocations: usize,
+}
+// backends without size classes report every allocation as large
+// and their whole heap as the fallback heap
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub backend: HeapBackend,
+    pub heap_start: usize,
+    pub heap_size: usize,
+    pub size_classes: [SizeClassStats; BLOCK_SIZES.len()],
+    pub live_large: usize,
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    pub fallback_used: usize,
+    pub fallback_free: usize,
+}
+// returns a snapshot of the global allocator counters
+pub fn stats() -> HeapStats {
+    match ALLOCATOR.backend() {
+        HeapBackend::Bump => ALLOCATOR.bump.lock().stats(),
+        HeapBackend::LinkedList => ALLOCATOR.linked_list.lock().stats(),
+        HeapBackend::FixedSizeBlock => ALLOCATOR.fixed_size_block.lock().stats(),
+        HeapBackend::Guarded => ALLOCATOR.guarded.lock().stats(),
+    }
+}
+// returns all cached free blocks to the fallback heap, returns bytes released
+pub fn trim() -> usize {
+    match ALLOCATOR.backend() {
+        HeapBackend::FixedSizeBlock => ALLOCATOR.fixed_size_block.lock().trim(),
+        HeapBackend::Bump | HeapBackend::LinkedList | HeapBackend::Guarded => 0,
+    }
+}
+// checks the guarded heap for overwritten red zones, panicking on the first
+// one; returns how many allocations were checked, 0 for other backends
+pub fn check_heap() -> usize {
+    match ALLOCATOR.backend() {
+        HeapBackend::Guarded => ALLOCATOR.guarded.lock().check(),
+        HeapBackend::Bump | HeapBackend::LinkedList | HeapBackend::FixedSizeBlock => 0,
+    }
+}
+// sets the maximum heap size, clamped to the reserved window
+pub fn set_heap_limit(bytes: usize) {
+    HEAP_LIMIT.store(bytes.min(HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+// maps more memory above a heap's top so that at least the second argument
+// in bytes fits, returns how many bytes were added; allocators without one
+// keep the size they were initialized with
+pub type GrowHeap = fn(usize, usize) -> usize;
+// grows the global heap inside its reserved window, 0 once the limit is reached
+fn grow_heap(heap_top: usize, min_bytes: usize) -> usize {
+    let limit_top = HEAP_START + heap_limit();
+    let wanted = align_up(min_bytes.max(HEAP_GROWTH_STEP), 4096);
+    let available = limit_top.saturating_sub(heap_top);
+    if available < align_up(min_bytes, 4096) {
+        return 0;
+    }
+    let bytes = wanted.min(available);
+    let mapped = memory::with_mapper(|mapper| map_heap_pages(mapper, heap_top, bytes));
+    match mapped {
+        Ok(()) => bytes,
+        Err(_) => 0,
+    }
+}
+fn page_range(start: usize, size: usize) -> impl Iterator<Item = Page<Size4KiB>> {
+    let start = VirtAddr::new(start as u64);
+    let end = start + size - 1u64;
+    Page::range_inclusive(Page::containing_address(start), Page::containing_address(end))
+}
+// heap pages are never executable, large growth steps use huge pages
+// where the alignment allows
+fn map_heap_pages(
+    mapper: &mut OffsetPageTable<'static>,
+    start: usize,
+    size: usize,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = VirtAddr::new(start as u64);
+    huge::map_zeroed_range(mapper, start, size as u64, flags, PageSizeKind::largest_supported())
+}
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
@@ -49,11 +273,42 @@ impl<A> Locked<A> {
             inner: spin::Mutex::new(inner),
         }
     }
-    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
-        self.inner.lock()
+    pub fn lock(&self) -> LockedGuard<'_, A> {
+        use x86_64::instructions::interrupts;
+        let enable = interrupts::are_enabled();
+        interrupts::disable();
+        LockedGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            enable,
+        }
+    }
+}
+// keeps interrupts off while the lock is held: a thread preempted inside the
+// allocator would leave others that allocate with interrupts off spinning
+pub struct LockedGuard<'a, A> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, A>>,
+    enable: bool,
+}
+impl<A> Deref for LockedGuard<'_, A> {
+    type Target = A;
+    fn deref(&self) -> &A {
+        &self.guard
+    }
+}
+impl<A> DerefMut for LockedGuard<'_, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        &mut self.guard
+    }
+}
+impl<A> Drop for LockedGuard<'_, A> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.enable {
+            x86_64::instructions::interrupts::enable();
+        }
     }
 }
 // aligns an address upwards to alignment
-fn align_up(addr: usize, align: usize) -> usize {
+const fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
@@ -1,11 +1,14 @@
-use super::{align_up, Locked};
+use super::{align_up, GrowHeap, HeapBackend, HeapStats, Locked, SizeClassStats};
+use super::fixed_size_block::BLOCK_SIZES;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 pub struct BumpAllocator {
     heap_start: usize,
     heap_end: usize,
     next: usize,
+    grow: Option<GrowHeap>,
     allocations: usize,
+    peak_bytes_in_use: usize,
 }
 impl BumpAllocator {
     pub const fn new() -> Self {
@@ -13,7 +16,9 @@ impl BumpAllocator {
             heap_start: 0,
             heap_end: 0,
             next: 0,
+            grow: None,
             allocations: 0,
+            peak_bytes_in_use: 0,
         }
     }
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
@@ -21,6 +26,24 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+    // lets the heap map more memory at its top when it runs out
+    pub fn set_growth(&mut self, grow: GrowHeap) {
+        self.grow = Some(grow);
+    }
+    pub fn stats(&self) -> HeapStats {
+        let used = self.next - self.heap_start;
+        HeapStats {
+            backend: HeapBackend::Bump,
+            heap_start: self.heap_start,
+            heap_size: self.heap_end - self.heap_start,
+            size_classes: [SizeClassStats::default(); BLOCK_SIZES.len()],
+            live_large: self.allocations,
+            bytes_in_use: used,
+            peak_bytes_in_use: self.peak_bytes_in_use,
+            fallback_used: used,
+            fallback_free: self.heap_end - self.next,
+        }
+    }
 }
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
@@ -31,10 +54,15 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
             None => return ptr::null_mut(),
         };
         if alloc_end > bump.heap_end {
-            ptr::null_mut() 
+            let heap_end = bump.heap_end;
+            bump.heap_end += bump.grow.map_or(0, |grow| grow(heap_end, alloc_end - heap_end));
+        }
+        if alloc_end > bump.heap_end {
+            ptr::null_mut()
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.peak_bytes_in_use = bump.peak_bytes_in_use.max(alloc_end - bump.heap_start);
             alloc_start as *mut u8
         }
     }
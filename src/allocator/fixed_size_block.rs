@@ -1,13 +1,21 @@
-use super::Locked;
+use super::{GrowHeap, HeapBackend, HeapStats, Locked, SizeClassStats};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr, ptr::NonNull};
 struct ListNode {
     next: Option<&'static mut ListNode>,
 }
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+// free blocks beyond this many bytes per size class go back to the fallback heap
+const FREE_LIST_MAX_BYTES: usize = 16 * 1024;
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    grow: Option<GrowHeap>,
+    free_blocks: [usize; BLOCK_SIZES.len()],
+    live_blocks: [usize; BLOCK_SIZES.len()],
+    live_large: usize,
+    bytes_in_use: usize,
+    peak_bytes_in_use: usize,
 }
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
@@ -15,17 +23,89 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            grow: None,
+            free_blocks: [0; BLOCK_SIZES.len()],
+            live_blocks: [0; BLOCK_SIZES.len()],
+            live_large: 0,
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
         }
     }
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         unsafe { self.fallback_allocator.init(heap_start, heap_size); }
     }
+    // lets the fallback heap map more memory at its top when it runs out
+    pub fn set_growth(&mut self, grow: GrowHeap) {
+        self.grow = Some(grow);
+    }
+    // returns a copy of the allocator counters
+    pub fn stats(&self) -> HeapStats {
+        let mut size_classes = [SizeClassStats::default(); BLOCK_SIZES.len()];
+        for (index, class) in size_classes.iter_mut().enumerate() {
+            *class = SizeClassStats {
+                block_size: BLOCK_SIZES[index],
+                free_blocks: self.free_blocks[index],
+                live_allocations: self.live_blocks[index],
+            };
+        }
+        HeapStats {
+            backend: HeapBackend::FixedSizeBlock,
+            heap_start: self.fallback_allocator.bottom(),
+            heap_size: self.fallback_allocator.size(),
+            size_classes,
+            live_large: self.live_large,
+            bytes_in_use: self.bytes_in_use,
+            peak_bytes_in_use: self.peak_bytes_in_use,
+            fallback_used: self.fallback_allocator.used(),
+            fallback_free: self.fallback_allocator.free(),
+        }
+    }
+    // hands every cached free block back to the fallback heap,
+    // returns the number of bytes released
+    pub fn trim(&mut self) -> usize {
+        let mut released = 0;
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            while let Some(node) = self.list_heads[index].take() {
+                self.list_heads[index] = node.next.take();
+                self.free_blocks[index] -= 1;
+                let ptr = NonNull::from(node).cast::<u8>();
+                unsafe {
+                    self.fallback_allocator.deallocate(ptr, block_layout(index));
+                }
+                released += block_size;
+            }
+        }
+        released
+    }
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        // cached blocks may be enough once merged back into the fallback heap
+        if self.trim() > 0 {
+            if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+        }
+        // out of space, map more pages and retry once
+        let needed = layout.size() + layout.align();
+        let grown = self.grow.map_or(0, |grow| grow(self.fallback_allocator.top(), needed));
+        if grown == 0 {
+            return ptr::null_mut();
+        }
+        unsafe { self.fallback_allocator.extend(grown); }
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
         }
     }
+    fn record_alloc(&mut self, bytes: usize) {
+        self.bytes_in_use += bytes;
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+    }
+}
+fn block_layout(index: usize) -> Layout {
+    Layout::from_size_align(BLOCK_SIZES[index], BLOCK_SIZES[index]).unwrap()
 }
 fn list_index(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
@@ -34,30 +114,46 @@ fn list_index(layout: &Layout) -> Option<usize> {
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
                 match allocator.list_heads[index].take() {
                     Some(node) => {
                         allocator.list_heads[index] = node.next.take();
+                        allocator.free_blocks[index] -= 1;
                         node as *mut ListNode as *mut u8
                     }
-                    None => {
-                        
-                        let block_size = BLOCK_SIZES[index];
-                        
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align)
-                            .unwrap();
-                        allocator.fallback_alloc(layout)
-                    }
+                    None => allocator.fallback_alloc(block_layout(index)),
                 }
             }
             None => allocator.fallback_alloc(layout),
+        };
+        if !ptr.is_null() {
+            match list_index(&layout) {
+                Some(index) => {
+                    allocator.live_blocks[index] += 1;
+                    allocator.record_alloc(BLOCK_SIZES[index]);
+                }
+                None => {
+                    allocator.live_large += 1;
+                    allocator.record_alloc(layout.size());
+                }
+            }
         }
+        ptr
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
         match list_index(&layout) {
+            Some(index) if (allocator.free_blocks[index] + 1) * BLOCK_SIZES[index]
+                > FREE_LIST_MAX_BYTES =>
+            {
+                let ptr = NonNull::new(ptr).unwrap();
+                unsafe {
+                    allocator.fallback_allocator.deallocate(ptr, block_layout(index));
+                }
+                allocator.live_blocks[index] -= 1;
+                allocator.bytes_in_use -= BLOCK_SIZES[index];
+            }
             Some(index) => {
                 let new_node = ListNode {
                     next: allocator.list_heads[index].take(),
@@ -70,12 +166,17 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                     new_node_ptr.write(new_node);
                     allocator.list_heads[index] = Some(&mut *new_node_ptr);
                 }
+                allocator.free_blocks[index] += 1;
+                allocator.live_blocks[index] -= 1;
+                allocator.bytes_in_use -= BLOCK_SIZES[index];
             }
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
                 unsafe {
                     allocator.fallback_allocator.deallocate(ptr, layout);
                 }
+                allocator.live_large -= 1;
+                allocator.bytes_in_use -= layout.size();
             }
         }
     }
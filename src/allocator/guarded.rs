@@ -0,0 +1,228 @@
+// debug allocator that puts every allocation on its own pages, right below
+// an unmapped guard page, with canary bytes filling the rest of the slot
+use super::{align_up, page_range, HeapBackend, HeapStats, Locked, SizeClassStats};
+use super::fixed_size_block::BLOCK_SIZES;
+use crate::memory::{self, huge::{self, PageSizeKind}, GlobalFrameAllocator};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+use x86_64::{
+    structures::paging::{FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+// virtual window for guarded allocations, addresses in it are never reused
+// so a use after free hits unmapped memory as well
+pub const GUARDED_HEAP_START: usize = 0x_4800_0000_0000;
+pub const GUARDED_HEAP_SIZE: usize = 64 * 1024 * 1024 * 1024;
+const PAGE_SIZE: usize = 4096;
+const HEADER_MAGIC: u64 = 0x6775_6172_6465_6421;
+const CANARY: u8 = 0xca;
+// smallest gap of canary bytes between the header and the object
+const MIN_RED_ZONE: usize = 16;
+// lives at the start of the first page of every slot
+#[repr(C)]
+struct Header {
+    magic: u64,
+    id: u64,
+    data_start: usize,
+    pages: usize,
+    size: usize,
+    align: usize,
+}
+const OBJECT_OFFSET: usize = mem::size_of::<Header>() + MIN_RED_ZONE;
+// a fault in the guard page behind a live allocation
+#[derive(Debug, Clone, Copy)]
+pub struct Overflow {
+    pub id: u64,
+    pub ptr: usize,
+    pub size: usize,
+    // offset of the faulting access from the start of the object
+    pub offset: usize,
+}
+pub struct GuardedAllocator {
+    next: usize,
+    next_id: u64,
+    allocations: usize,
+    mapped_pages: usize,
+    bytes_in_use: usize,
+    peak_bytes_in_use: usize,
+}
+impl GuardedAllocator {
+    pub const fn new() -> Self {
+        GuardedAllocator {
+            next: GUARDED_HEAP_START,
+            next_id: 0,
+            allocations: 0,
+            mapped_pages: 0,
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+        }
+    }
+    pub fn stats(&self) -> HeapStats {
+        let mapped = self.mapped_pages * PAGE_SIZE;
+        HeapStats {
+            backend: HeapBackend::Guarded,
+            heap_start: GUARDED_HEAP_START,
+            heap_size: mapped,
+            size_classes: [SizeClassStats::default(); BLOCK_SIZES.len()],
+            live_large: self.allocations,
+            bytes_in_use: self.bytes_in_use,
+            peak_bytes_in_use: self.peak_bytes_in_use,
+            fallback_used: self.bytes_in_use,
+            fallback_free: mapped - self.bytes_in_use,
+        }
+    }
+    // checks the red zones of every live allocation, which catches overflows
+    // into the padding in front of the guard page of objects never freed;
+    // panics on the first corruption, returns how many allocations it checked
+    pub fn check(&self) -> usize {
+        let mut checked = 0;
+        let mut addr = GUARDED_HEAP_START;
+        while addr < self.next {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr as u64));
+            // freed slots are unmapped
+            if memory::with_mapper(|mapper| mapper.translate_page(page).is_err()) {
+                addr += PAGE_SIZE;
+                continue;
+            }
+            let header = unsafe { &*(addr as *const Header) };
+            assert!(header.magic == HEADER_MAGIC, "HEAP CORRUPTION: bad header at {:#x}", addr);
+            let object = object_start(header.data_start, header.pages, header.size, header.align);
+            check_red_zones(header, object);
+            checked += 1;
+            addr = header.data_start + (header.pages + 1) * PAGE_SIZE;
+        }
+        checked
+    }
+}
+impl Default for GuardedAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+// looks up the allocation whose guard page contains `addr`,
+// only uses try_lock so it can run inside the page fault handler
+pub fn find_overflow(addr: VirtAddr) -> Option<Overflow> {
+    let addr = addr.as_u64() as usize;
+    if !(GUARDED_HEAP_START..GUARDED_HEAP_START + GUARDED_HEAP_SIZE).contains(&addr) {
+        return None;
+    }
+    let guard = addr & !(PAGE_SIZE - 1);
+    memory::try_with_mapper(|mapper| {
+        let is_mapped = |start: usize| {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(start as u64));
+            mapper.translate_page(page).is_ok()
+        };
+        if is_mapped(guard) {
+            return None;
+        }
+        // the slot start is the lowest mapped page below the guard
+        let mut first = None;
+        let mut page = guard;
+        while page > GUARDED_HEAP_START && is_mapped(page - PAGE_SIZE) {
+            page -= PAGE_SIZE;
+            first = Some(page);
+        }
+        let header = unsafe { &*(first? as *const Header) };
+        if header.magic != HEADER_MAGIC || header.data_start + header.pages * PAGE_SIZE != guard {
+            return None;
+        }
+        let ptr = object_start(header.data_start, header.pages, header.size, header.align);
+        Some(Overflow {
+            id: header.id,
+            ptr,
+            size: header.size,
+            offset: addr - ptr,
+        })
+    })?
+}
+// panics if anything but the object was written to in its slot
+fn check_red_zones(header: &Header, object: usize) {
+    let header_end = header.data_start + mem::size_of::<Header>();
+    let data_end = header.data_start + header.pages * PAGE_SIZE;
+    let mut red_zones = (header_end..object).chain(object + header.size..data_end);
+    if let Some(addr) = red_zones.find(|&a| unsafe { *(a as *const u8) } != CANARY) {
+        panic!(
+            "HEAP RED ZONE CORRUPTED: allocation #{} at {:#x} ({} bytes) was overwritten at {:#x}",
+            header.id, object, header.size, addr
+        );
+    }
+}
+// objects end as close to the guard page as their alignment allows
+fn object_start(data_start: usize, pages: usize, size: usize, align: usize) -> usize {
+    (data_start + pages * PAGE_SIZE - size) & !(align - 1)
+}
+unsafe impl GlobalAlloc for Locked<GuardedAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // the slot end is recovered from the object end, which needs align <= page
+        if layout.align() > PAGE_SIZE {
+            return ptr::null_mut();
+        }
+        let size = layout.size().max(1);
+        let pages = align_up(OBJECT_OFFSET + size + layout.align(), PAGE_SIZE) / PAGE_SIZE;
+        let mut allocator = self.lock();
+        let data_start = allocator.next;
+        let slot_end = data_start + (pages + 1) * PAGE_SIZE;
+        if slot_end > GUARDED_HEAP_START + GUARDED_HEAP_SIZE {
+            return ptr::null_mut();
+        }
+        // always 4 KiB pages, the fault lookup walks them one by one
+        let mapped = memory::with_mapper(|mapper| {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            let start = VirtAddr::new(data_start as u64);
+            huge::map_zeroed_range(mapper, start, (pages * PAGE_SIZE) as u64, flags, PageSizeKind::Size4KiB)
+        });
+        if mapped.is_err() {
+            return ptr::null_mut();
+        }
+        // the page after the data pages stays unmapped and becomes the guard
+        allocator.next = slot_end;
+        let id = allocator.next_id;
+        allocator.next_id += 1;
+        let object = object_start(data_start, pages, size, layout.align());
+        let data_end = data_start + pages * PAGE_SIZE;
+        unsafe {
+            (data_start as *mut Header).write(Header {
+                magic: HEADER_MAGIC,
+                id,
+                data_start,
+                pages,
+                size,
+                align: layout.align(),
+            });
+            let header_end = data_start + mem::size_of::<Header>();
+            ptr::write_bytes(header_end as *mut u8, CANARY, object - header_end);
+            ptr::write_bytes((object + size) as *mut u8, CANARY, data_end - object - size);
+        }
+        allocator.allocations += 1;
+        allocator.mapped_pages += pages;
+        allocator.bytes_in_use += size;
+        allocator.peak_bytes_in_use = allocator.peak_bytes_in_use.max(allocator.bytes_in_use);
+        object as *mut u8
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let size = layout.size().max(1);
+        let object = ptr as usize;
+        let data_end = align_up(object + size, PAGE_SIZE);
+        let pages = align_up(OBJECT_OFFSET + size + layout.align(), PAGE_SIZE) / PAGE_SIZE;
+        let data_start = data_end - pages * PAGE_SIZE;
+        let header = unsafe { &*(data_start as *const Header) };
+        assert!(
+            header.magic == HEADER_MAGIC && header.data_start == data_start && header.size == size,
+            "HEAP CORRUPTION: bad header for allocation at {:#x}",
+            object
+        );
+        check_red_zones(header, object);
+        memory::with_mapper(|mapper| {
+            for page in page_range(data_start, pages * PAGE_SIZE) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                }
+            }
+        });
+        let mut allocator = self.lock();
+        allocator.allocations -= 1;
+        allocator.mapped_pages -= pages;
+        allocator.bytes_in_use -= size;
+    }
+}
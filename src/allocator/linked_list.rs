@@ -1,4 +1,5 @@
-use super::{align_up, Locked};
+use super::{align_up, GrowHeap, HeapBackend, HeapStats, Locked, SizeClassStats};
+use super::fixed_size_block::BLOCK_SIZES;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 struct ListNode {
@@ -16,54 +17,160 @@ impl ListNode {
         self.start_addr() + self.size
     }
 }
+// how a free region is picked for an allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    FirstFit,
+    BestFit,
+}
+// free regions are kept sorted by address so neighbours can be merged
 pub struct LinkedListAllocator {
     head: ListNode,
+    strategy: FitStrategy,
+    heap_start: usize,
+    heap_end: usize,
+    grow: Option<GrowHeap>,
+    allocations: usize,
+    bytes_in_use: usize,
+    peak_bytes_in_use: usize,
 }
 impl LinkedListAllocator {
     pub const fn new() -> Self {
+        Self::with_strategy(FitStrategy::FirstFit)
+    }
+    pub const fn with_strategy(strategy: FitStrategy) -> Self {
         Self {
             head: ListNode::new(0),
+            strategy,
+            heap_start: 0,
+            heap_end: 0,
+            grow: None,
+            allocations: 0,
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
         }
     }
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
         self.add_free_region(heap_start, heap_size);
     }
+    // lets the heap map more memory at its top when it runs out
+    pub fn set_growth(&mut self, grow: GrowHeap) {
+        self.grow = Some(grow);
+    }
+    pub fn set_strategy(&mut self, strategy: FitStrategy) {
+        self.strategy = strategy;
+    }
+    pub fn stats(&self) -> HeapStats {
+        let heap_size = self.heap_end - self.heap_start;
+        HeapStats {
+            backend: HeapBackend::LinkedList,
+            heap_start: self.heap_start,
+            heap_size,
+            size_classes: [SizeClassStats::default(); BLOCK_SIZES.len()],
+            live_large: self.allocations,
+            bytes_in_use: self.bytes_in_use,
+            peak_bytes_in_use: self.peak_bytes_in_use,
+            fallback_used: self.bytes_in_use,
+            fallback_free: heap_size - self.bytes_in_use,
+        }
+    }
+    // returns the number of separate free regions, 1 means no fragmentation
+    pub fn free_regions(&self) -> usize {
+        let mut count = 0;
+        let mut current = self.head.next.as_deref();
+        while let Some(region) = current {
+            count += 1;
+            current = region.next.as_deref();
+        }
+        count
+    }
+    // maps more pages at the top of the heap and adds them as a free region
+    fn grow(&mut self, min_bytes: usize) -> bool {
+        let grown = self.grow.map_or(0, |grow| grow(self.heap_end, min_bytes));
+        if grown == 0 {
+            return false;
+        }
+        unsafe {
+            self.add_free_region(self.heap_end, grown);
+        }
+        self.heap_end += grown;
+        true
+    }
+    // inserts a region in address order, merging it with adjacent free regions
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
         
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        let mut current = &mut self.head;
+        let mut current_is_head = true;
+        while current.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+            current_is_head = false;
+        }
+        if let Some(next) = current.next.as_ref() {
+            assert!(addr + size <= next.start_addr(), "freed region overlaps free list");
+        }
+        if !current_is_head {
+            assert!(current.end_addr() <= addr, "freed region overlaps free list");
+        }
+        if !current_is_head && current.end_addr() == addr {
+            current.size += size;
+        } else {
+            let mut node = ListNode::new(size);
+            node.next = current.next.take();
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr);
+            current = current.next.as_mut().unwrap();
+        }
+        if let Some(next) = current.next.take() {
+            if current.end_addr() == next.start_addr() {
+                current.size += next.size;
+                current.next = next.next.take();
+            } else {
+                current.next = Some(next);
+            }
+        }
     }
     fn find_region(&mut self, size: usize, align: usize)
         -> Option<(&'static mut ListNode, usize)>
     {
         
-        let mut current = &mut self.head;
-        
-        while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
-                
-                let next = region.next.take();
-                let ret = Some((current.next.take().unwrap(), alloc_start));
-                current.next = next;
-                return ret;
-            } else {
-                
-                current = current.next.as_mut().unwrap();
+        let mut chosen: Option<(usize, usize)> = None;
+        let mut current = self.head.next.as_deref();
+        while let Some(region) = current {
+            if Self::alloc_from_region(region, size, align).is_ok() {
+                let better = chosen.is_none_or(|(_, chosen_size)| region.size < chosen_size);
+                if better {
+                    chosen = Some((region.start_addr(), region.size));
+                }
+                if self.strategy == FitStrategy::FirstFit || region.size == size {
+                    break;
+                }
             }
+            current = region.next.as_deref();
         }
-        
-        None
+        let (target, _) = chosen?;
+        let mut current = &mut self.head;
+        while current.next.as_ref().unwrap().start_addr() != target {
+            current = current.next.as_mut().unwrap();
+        }
+        let region = current.next.take().unwrap();
+        current.next = region.next.take();
+        let alloc_start = Self::alloc_from_region(region, size, align).unwrap();
+        Some((region, alloc_start))
     }
     fn alloc_from_region(region: &ListNode, size: usize, align: usize)
         -> Result<usize, ()>
     {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let front_size = alloc_start - region.start_addr();
+        if front_size > 0 && front_size < mem::size_of::<ListNode>() {
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
         if alloc_end > region.end_addr() {
             
@@ -92,14 +199,27 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         
         let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+        let mut found = allocator.find_region(size, align);
+        if found.is_none() && allocator.grow(size + align + mem::size_of::<ListNode>()) {
+            found = allocator.find_region(size, align);
+        }
+        if let Some((region, alloc_start)) = found {
+            let region_start = region.start_addr();
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
             let excess_size = region.end_addr() - alloc_end;
+            if alloc_start > region_start {
+                unsafe {
+                    allocator.add_free_region(region_start, alloc_start - region_start);
+                }
+            }
             if excess_size > 0 {
                 unsafe {
                     allocator.add_free_region(alloc_end, excess_size);
                 }
             }
+            allocator.allocations += 1;
+            allocator.bytes_in_use += size;
+            allocator.peak_bytes_in_use = allocator.peak_bytes_in_use.max(allocator.bytes_in_use);
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
@@ -108,6 +228,9 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         
         let (size, _) = LinkedListAllocator::size_align(layout);
-        unsafe { self.lock().add_free_region(ptr as usize, size) }
+        let mut allocator = self.lock();
+        unsafe { allocator.add_free_region(ptr as usize, size) }
+        allocator.allocations -= 1;
+        allocator.bytes_in_use -= size;
     }
 }
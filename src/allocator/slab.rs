@@ -0,0 +1,285 @@
+// typed object caches carved out of whole physical frames
+use crate::memory::{self, GlobalFrameAllocator};
+use alloc::vec::Vec;
+use core::{
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+use super::align_up;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+const FRAME_SIZE: usize = 4096;
+// large objects get multi-frame slabs so a slab still holds a few of them
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub objects_free: usize,
+    pub total_allocations: u64,
+}
+pub trait SlabInfo: Sync {
+    fn stats(&self) -> SlabStats;
+}
+static CACHES: Mutex<Vec<&'static dyn SlabInfo>> = Mutex::new(Vec::new());
+// returns statistics for every cache that has handed out an object
+pub fn all_stats() -> Vec<SlabStats> {
+    CACHES.lock().iter().map(|cache| cache.stats()).collect()
+}
+// free list link, kept behind the object so a free slot can still hold a
+// constructed object
+struct FreeObject {
+    next: *mut FreeObject,
+}
+// lives at the start of every slab, followed by the object slots
+struct SlabHeader {
+    next_partial: *mut SlabHeader,
+    free_list: *mut FreeObject,
+    in_use: usize,
+    on_partial: bool,
+    first_frame: PhysFrame,
+}
+struct SlabState {
+    partial: *mut SlabHeader,
+    slabs: usize,
+    objects_in_use: usize,
+    objects_free: usize,
+    total_allocations: u64,
+}
+// the raw pointers only ever point into slabs owned by this state
+unsafe impl Send for SlabState {}
+// with a constructor every slot holds a constructed object from the moment
+// its slab is created: objects go back to the cache as they are, without
+// being dropped, and are only dropped when their slab is freed
+pub struct SlabCache<T> {
+    name: &'static str,
+    constructor: Option<fn() -> T>,
+    state: Mutex<SlabState>,
+    registered: AtomicBool,
+    _marker: PhantomData<*const T>,
+}
+// objects are handed out to whichever thread allocates, and constructed ones
+// are dropped by whichever thread frees their slab, so only caches of `Send`
+// objects can be shared and allocate
+unsafe impl<T: Send> Send for SlabCache<T> {}
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+impl<T> SlabCache<T> {
+    const SLOT_ALIGN: usize = max(mem::align_of::<T>(), mem::align_of::<FreeObject>());
+    const LINK_OFFSET: usize = align_up(mem::size_of::<T>(), mem::align_of::<FreeObject>());
+    const SLOT_SIZE: usize = align_up(Self::LINK_OFFSET + mem::size_of::<FreeObject>(), Self::SLOT_ALIGN);
+    const FIRST_SLOT: usize = align_up(mem::size_of::<SlabHeader>(), Self::SLOT_ALIGN);
+    const SLAB_FRAMES: usize = max(
+        1,
+        (Self::FIRST_SLOT + Self::SLOT_SIZE * MIN_OBJECTS_PER_SLAB).div_ceil(FRAME_SIZE),
+    );
+    const OBJECTS_PER_SLAB: usize =
+        (Self::SLAB_FRAMES * FRAME_SIZE - Self::FIRST_SLOT) / Self::SLOT_SIZE;
+    pub const fn new(name: &'static str) -> Self {
+        SlabCache {
+            name,
+            constructor: None,
+            state: Mutex::new(SlabState {
+                partial: ptr::null_mut(),
+                slabs: 0,
+                objects_in_use: 0,
+                objects_free: 0,
+                total_allocations: 0,
+            }),
+            registered: AtomicBool::new(false),
+            _marker: PhantomData,
+        }
+    }
+    // a cache whose slabs come with every object already constructed
+    pub const fn with_constructor(name: &'static str, constructor: fn() -> T) -> Self {
+        let mut cache = Self::new(name);
+        cache.constructor = Some(constructor);
+        cache
+    }
+    // moves `value` into a slab slot, returns None if no frames are left
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>>
+    where
+        T: Send,
+    {
+        let slot = self.alloc_slot()?;
+        match self.constructor {
+            Some(_) => drop(unsafe { ptr::replace(slot.object.as_ptr(), value) }),
+            None => unsafe { slot.object.as_ptr().write(value) },
+        }
+        Some(slot)
+    }
+    // hands out a constructed object as it was left by its last user
+    pub fn alloc_constructed(&'static self) -> Option<SlabBox<T>>
+    where
+        T: Send,
+    {
+        assert!(self.constructor.is_some(), "slab cache has no constructor");
+        self.alloc_slot()
+    }
+    fn alloc_slot(&'static self) -> Option<SlabBox<T>>
+    where
+        T: Send,
+    {
+        let (slab, object) = self.take_slot()?;
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            CACHES.lock().push(self);
+        }
+        Some(SlabBox { object, slab, cache: self })
+    }
+    fn take_slot(&self) -> Option<(NonNull<SlabHeader>, NonNull<T>)> {
+        assert!(Self::SLOT_ALIGN <= FRAME_SIZE, "slab objects can't be page-aligned");
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.partial.is_null() {
+                state.partial = self.new_slab()?;
+                state.slabs += 1;
+                state.objects_free += Self::OBJECTS_PER_SLAB;
+            }
+            let slab = unsafe { &mut *state.partial };
+            let object = slab.free_list;
+            slab.free_list = unsafe { (*object).next };
+            slab.in_use += 1;
+            if slab.free_list.is_null() {
+                state.partial = slab.next_partial;
+                slab.next_partial = ptr::null_mut();
+                slab.on_partial = false;
+            }
+            state.objects_in_use += 1;
+            state.objects_free -= 1;
+            state.total_allocations += 1;
+            Some((NonNull::from(slab), Self::object_of(object)))
+        })
+    }
+    unsafe fn free_slot(&self, slab: NonNull<SlabHeader>, object: NonNull<T>) {
+        let emptied = interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            let header = unsafe { &mut *slab.as_ptr() };
+            let node = Self::link_of(object);
+            unsafe { node.write(FreeObject { next: header.free_list }) };
+            header.free_list = node;
+            header.in_use -= 1;
+            state.objects_in_use -= 1;
+            state.objects_free += 1;
+            if !header.on_partial {
+                header.next_partial = state.partial;
+                header.on_partial = true;
+                state.partial = slab.as_ptr();
+            }
+            // keep one empty slab around, give the rest back to the frame allocator
+            if header.in_use == 0 && state.slabs > 1 {
+                let mut link = &mut state.partial;
+                while *link != slab.as_ptr() {
+                    link = unsafe { &mut (**link).next_partial };
+                }
+                *link = header.next_partial;
+                state.slabs -= 1;
+                state.objects_free -= Self::OBJECTS_PER_SLAB;
+                return Some(header.first_frame);
+            }
+            None
+        });
+        // the slab is unlinked, so its objects are dropped without the lock:
+        // their destructors may free into this cache as well
+        let Some(first_frame) = emptied else { return };
+        if self.constructor.is_some() {
+            let base = slab.as_ptr() as *mut u8;
+            for index in 0..Self::OBJECTS_PER_SLAB {
+                let object = unsafe { base.add(Self::FIRST_SLOT + index * Self::SLOT_SIZE) };
+                unsafe { ptr::drop_in_place(object as *mut T) };
+            }
+        }
+        unsafe { GlobalFrameAllocator.deallocate_contiguous(first_frame, Self::SLAB_FRAMES) };
+    }
+    // grabs frames for a new slab, constructs its objects if the cache has a
+    // constructor and threads the free list through its slots
+    fn new_slab(&self) -> Option<*mut SlabHeader> {
+        let frame = GlobalFrameAllocator.allocate_contiguous(Self::SLAB_FRAMES)?;
+        let base: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+        let mut free_list = ptr::null_mut();
+        for index in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let object = unsafe { base.add(Self::FIRST_SLOT + index * Self::SLOT_SIZE) } as *mut T;
+            if let Some(constructor) = self.constructor {
+                unsafe { object.write(constructor()) };
+            }
+            let link = Self::link_of(NonNull::new(object).unwrap());
+            unsafe { link.write(FreeObject { next: free_list }) };
+            free_list = link;
+        }
+        let header = base as *mut SlabHeader;
+        unsafe {
+            header.write(SlabHeader {
+                next_partial: ptr::null_mut(),
+                free_list,
+                in_use: 0,
+                on_partial: true,
+                first_frame: frame,
+            });
+        }
+        Some(header)
+    }
+}
+impl<T> SlabCache<T> {
+    fn link_of(object: NonNull<T>) -> *mut FreeObject {
+        unsafe { (object.as_ptr() as *mut u8).add(Self::LINK_OFFSET) as *mut FreeObject }
+    }
+    fn object_of(link: *mut FreeObject) -> NonNull<T> {
+        NonNull::new(unsafe { (link as *mut u8).sub(Self::LINK_OFFSET) } as *mut T).unwrap()
+    }
+}
+impl<T: Send> SlabInfo for SlabCache<T> {
+    fn stats(&self) -> SlabStats {
+        let state = interrupts::without_interrupts(|| {
+            let state = self.state.lock();
+            (state.slabs, state.objects_in_use, state.objects_free, state.total_allocations)
+        });
+        SlabStats {
+            name: self.name,
+            object_size: Self::SLOT_SIZE,
+            objects_per_slab: Self::OBJECTS_PER_SLAB,
+            slabs: state.0,
+            objects_in_use: state.1,
+            objects_free: state.2,
+            total_allocations: state.3,
+        }
+    }
+}
+// owning pointer to an object in a slab cache, like Box
+pub struct SlabBox<T: 'static> {
+    object: NonNull<T>,
+    slab: NonNull<SlabHeader>,
+    cache: &'static SlabCache<T>,
+}
+// like Box, it can move to other threads if the object can
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // constructed objects stay alive in the cache
+            if self.cache.constructor.is_none() {
+                ptr::drop_in_place(self.object.as_ptr());
+            }
+            self.cache.free_slot(self.slab, self.object);
+        }
+    }
+}
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
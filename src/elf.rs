@@ -0,0 +1,224 @@
+// loader for static ELF64 executables, mapped into the user window of an
+// address space
+use crate::memory::{vma::{self, VmaError, VmaKind}, AddressSpace};
+use crate::usermode::{self, UserExit};
+use alloc::{vec, vec::Vec};
+use core::mem;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+const SEGMENT_LOAD: u32 = 1;
+const FLAG_EXECUTE: u32 = 1;
+const FLAG_WRITE: u32 = 2;
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct FileHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_headers: u64,
+    section_headers: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+    section_header_size: u16,
+    section_header_count: u16,
+    section_names: u16,
+}
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    virtual_addr: u64,
+    physical_addr: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+#[derive(Debug)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    NotExecutable,
+    WrongMachine,
+    BadSegment,
+    // a segment would be both writable and executable
+    WritableAndExecutable,
+    OutsideUserWindow,
+    // the entry point isn't inside an executable segment
+    BadEntry,
+    ArgumentsTooLong,
+    Map(VmaError),
+}
+impl From<VmaError> for ElfError {
+    fn from(err: VmaError) -> Self {
+        ElfError::Map(err)
+    }
+}
+// where a loaded program starts
+#[derive(Debug, Clone, Copy)]
+pub struct Image {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+// loads `image` into a fresh address space and runs it, returning how it
+// left ring 3
+pub fn run(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<UserExit, ElfError> {
+    let mut space = AddressSpace::new()?;
+    let loaded = load(&mut space, image, argv, envp)?;
+    Ok(unsafe { usermode::enter_space(&space, loaded.entry, loaded.stack_pointer) })
+}
+// validates `image`, maps its loadable segments into `space` together with
+// a stack holding argc, argv, envp and an empty auxiliary vector like the
+// SysV ABI lays them out; on errors `space` may be left half populated
+pub fn load(space: &mut AddressSpace, image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Image, ElfError> {
+    let header: FileHeader = read(image, 0)?;
+    validate(&header)?;
+    let segments = (0..header.program_header_count as u64)
+        .map(|index| read::<ProgramHeader>(image, header.program_headers + index * header.program_header_size as u64))
+        .collect::<Result<Vec<_>, _>>()?;
+    let loadable: Vec<ProgramHeader> = segments
+        .into_iter()
+        .filter(|segment| segment.kind == SEGMENT_LOAD && segment.memory_size > 0)
+        .collect();
+    for segment in &loadable {
+        check_segment(image, segment)?;
+    }
+    let entry_in_code = loadable.iter().any(|segment| {
+        segment.flags & FLAG_EXECUTE != 0
+            && (segment.virtual_addr..segment.virtual_addr + segment.memory_size).contains(&header.entry)
+    });
+    if !entry_in_code {
+        return Err(ElfError::BadEntry);
+    }
+    for (start, end, elf_flags) in page_runs(&loadable)? {
+        let (name, flags) = segment_flags(elf_flags);
+        space.map_at(name, VirtAddr::new(start), end - start, flags, VmaKind::Anonymous)?;
+    }
+    for segment in &loadable {
+        let contents = &image[segment.offset as usize..(segment.offset + segment.file_size) as usize];
+        space.write_bytes(VirtAddr::new(segment.virtual_addr), contents)?;
+    }
+    let stack = usermode::map_stack(space)?;
+    let (stack_pointer, contents) = stack_image(stack.end.as_u64(), argv, envp)?;
+    space.write_bytes(VirtAddr::new(stack_pointer), &contents)?;
+    Ok(Image {
+        entry: VirtAddr::new(header.entry),
+        stack_pointer: VirtAddr::new(stack_pointer),
+    })
+}
+fn validate(header: &FileHeader) -> Result<(), ElfError> {
+    if header.ident[..4] != MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    if header.ident[4] != CLASS_64 {
+        return Err(ElfError::NotElf64);
+    }
+    if header.ident[5] != LITTLE_ENDIAN {
+        return Err(ElfError::NotLittleEndian);
+    }
+    if header.kind != TYPE_EXECUTABLE {
+        return Err(ElfError::NotExecutable);
+    }
+    if header.machine != MACHINE_X86_64 {
+        return Err(ElfError::WrongMachine);
+    }
+    if (header.program_header_size as usize) < mem::size_of::<ProgramHeader>() {
+        return Err(ElfError::BadSegment);
+    }
+    Ok(())
+}
+fn check_segment(image: &[u8], segment: &ProgramHeader) -> Result<(), ElfError> {
+    let file_end = segment.offset.checked_add(segment.file_size).ok_or(ElfError::BadSegment)?;
+    if segment.file_size > segment.memory_size || file_end > image.len() as u64 {
+        return Err(ElfError::BadSegment);
+    }
+    let end = segment.virtual_addr.checked_add(segment.memory_size).ok_or(ElfError::OutsideUserWindow)?;
+    if segment.virtual_addr < vma::USER_WINDOW_START || end > vma::USER_WINDOW_END {
+        return Err(ElfError::OutsideUserWindow);
+    }
+    if segment.flags & FLAG_WRITE != 0 && segment.flags & FLAG_EXECUTE != 0 {
+        return Err(ElfError::WritableAndExecutable);
+    }
+    Ok(())
+}
+// the pages the segments cover as (start, end, flags) runs; segments sharing
+// a page, as linkers lay them out without separate code pages, end up in one
+// run with the permissions of all of them
+fn page_runs(segments: &[ProgramHeader]) -> Result<Vec<(u64, u64, u32)>, ElfError> {
+    let mut sorted = segments.to_vec();
+    sorted.sort_by_key(|segment| segment.virtual_addr);
+    let mut runs: Vec<(u64, u64, u32)> = Vec::new();
+    for segment in sorted {
+        let start = segment.virtual_addr & !0xfff;
+        let end = (segment.virtual_addr + segment.memory_size).next_multiple_of(4096);
+        match runs.last_mut() {
+            Some(run) if start < run.1 => {
+                run.1 = run.1.max(end);
+                run.2 |= segment.flags;
+            }
+            _ => runs.push((start, end, segment.flags)),
+        }
+    }
+    if runs.iter().any(|&(_, _, flags)| flags & FLAG_WRITE != 0 && flags & FLAG_EXECUTE != 0) {
+        return Err(ElfError::WritableAndExecutable);
+    }
+    Ok(runs)
+}
+fn segment_flags(flags: u32) -> (&'static str, PageTableFlags) {
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if flags & FLAG_EXECUTE != 0 {
+        ("user code", user)
+    } else if flags & FLAG_WRITE != 0 {
+        ("user data", user | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+    } else {
+        ("user rodata", user | PageTableFlags::NO_EXECUTE)
+    }
+}
+// builds the initial stack below `top`: the strings at the very top, then
+// argc, the argv and envp pointer arrays and a lone AT_NULL entry
+fn stack_image(top: u64, argv: &[&str], envp: &[&str]) -> Result<(u64, Vec<u8>), ElfError> {
+    let strings: usize = argv.iter().chain(envp).map(|arg| arg.len() + 1).sum();
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2;
+    // leave most of the stack to the program
+    if (strings + words * 8) as u64 > usermode::USER_STACK_SIZE / 2 {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+    let strings_start = top - strings as u64;
+    let stack_pointer = (strings_start - words as u64 * 8) & !0xf;
+    let mut contents = vec![0u8; (top - stack_pointer) as usize];
+    let mut pointers = Vec::with_capacity(words);
+    pointers.push(argv.len() as u64);
+    let mut offset = (strings_start - stack_pointer) as usize;
+    for list in [argv, envp] {
+        for arg in list {
+            pointers.push(stack_pointer + offset as u64);
+            contents[offset..offset + arg.len()].copy_from_slice(arg.as_bytes());
+            offset += arg.len() + 1;
+        }
+        pointers.push(0);
+    }
+    pointers.extend([0, 0]);
+    for (index, pointer) in pointers.iter().enumerate() {
+        contents[index * 8..index * 8 + 8].copy_from_slice(&pointer.to_le_bytes());
+    }
+    Ok((stack_pointer, contents))
+}
+// reads a header at `offset`, the image may have any alignment
+fn read<T: Copy>(image: &[u8], offset: u64) -> Result<T, ElfError> {
+    let end = offset.checked_add(mem::size_of::<T>() as u64).ok_or(ElfError::Truncated)?;
+    if end > image.len() as u64 {
+        return Err(ElfError::Truncated);
+    }
+    Ok(unsafe { image.as_ptr().add(offset as usize).cast::<T>().read_unaligned() })
+}
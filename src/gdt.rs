@@ -3,41 +3,95 @@ use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use lazy_static::lazy_static;
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
+// the lowest page of each stack becomes a guard page in `protect_stacks`
+const STACK_SIZE: usize = 4096 * 6;
+// only ever accessed through raw pointers by the cpu
+#[allow(dead_code)]
+#[repr(align(4096))]
+struct Stack([u8; STACK_SIZE]);
+static mut DOUBLE_FAULT_STACK: Stack = Stack([0; STACK_SIZE]);
+// where the cpu switches to when an interrupt or trap leaves ring 3
+static mut PRIVILEGE_STACK: Stack = Stack([0; STACK_SIZE]);
+// filled in when the gdt is built; the privilege stack entry changes with
+// every thread switch, so it can't live behind a shared reference
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+fn init_tss() {
+    unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            let stack_start = VirtAddr::from_ptr(&raw const DOUBLE_FAULT_STACK);
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
-        tss
-    };
+        TSS.privilege_stack_table[0] = boot_privilege_stack_top();
+    }
+}
+// unmaps the bottom page of the interrupt stacks, needs memory::init first
+pub fn protect_stacks() {
+    use crate::memory::guard;
+    unsafe {
+        guard::protect_static_stack(
+            "double fault stack",
+            VirtAddr::from_ptr(&raw const DOUBLE_FAULT_STACK),
+        );
+        guard::protect_static_stack(
+            "privilege stack",
+            VirtAddr::from_ptr(&raw const PRIVILEGE_STACK),
+        );
+    }
 }
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
 use x86_64::structures::gdt::SegmentSelector;
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        init_tss();
         let mut gdt = GlobalDescriptorTable::new();
+        // sysret expects user data right before user code
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(unsafe { Descriptor::tss_segment_unchecked(&raw const TSS) });
+        (gdt, Selectors { code_selector, data_selector, user_code_selector, user_data_selector, tss_selector })
     };
 }
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
+// code and data selectors for ring 0
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+// code and data selectors for ring 3, with the requested privilege level set
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+// top of the stack the cpu enters the kernel on from ring 3
+pub fn privilege_stack_top() -> VirtAddr {
+    lazy_static::initialize(&GDT);
+    unsafe { TSS.privilege_stack_table[0] }
+}
+// the privilege stack the boot thread uses
+pub fn boot_privilege_stack_top() -> VirtAddr {
+    VirtAddr::from_ptr(&raw const PRIVILEGE_STACK) + STACK_SIZE
+}
+// points the cpu at another privilege stack, each thread has its own
+pub fn set_privilege_stack_top(top: VirtAddr) {
+    lazy_static::initialize(&GDT);
+    unsafe { TSS.privilege_stack_table[0] = top };
+}
 // initializes the global descriptor table
 pub fn init() {
     use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::segmentation::{CS, SS, Segment};
     
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
@@ -1,9 +1,10 @@
 // idt setup and interrupt handlers
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 use futures_util::task::AtomicWaker;
-use crate::hlt_loop;
 use crate::{println, print};
 use crate::gdt;
+use crate::{allocator, memory, syscall, usermode::{self, UserExit}};
+use x86_64::VirtAddr;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
@@ -33,7 +34,19 @@ lazy_static! {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
+        // no stack of its own: the handler may fault again while resolving
+        // a fault, and a fresh IST frame would overwrite the outer one; a
+        // kernel stack overflow can't push the frame and ends up as a double
+        // fault, which reports it
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        // system calls through `int 0x80`, which ring 3 may raise itself
+        unsafe {
+            idt[usize::from(syscall::INTERRUPT_VECTOR)]
+                .set_handler_addr(syscall::interrupt_handler_addr())
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+        }
         idt[InterruptIndex::Timer.as_usize()]
             .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
@@ -45,12 +58,28 @@ lazy_static! {
 pub fn init_idt() {
     IDT.load();
 }
-// handler for double faults (fatal errors)
+// handler for double faults (fatal errors), including a kernel stack
+// running into its guard page
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
+    use x86_64::registers::control::Cr2;
+    report_guard_fault(Cr2::read(), stack_frame.stack_pointer);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
+// panics with a targeted report if `addr` hit a guard page
+fn report_guard_fault(addr: VirtAddr, stack_pointer: VirtAddr) {
+    if let Some(overflow) = allocator::guarded::find_overflow(addr) {
+        panic!(
+            "HEAP OVERFLOW: access at offset {} of allocation #{} ({:#x}, {} bytes)",
+            overflow.offset, overflow.id, overflow.ptr, overflow.size
+        );
+    }
+    // only registered guard pages count, anything else is a plain fault
+    if let Some(guard) = memory::guard::find(addr) {
+        panic!("STACK OVERFLOW in {}: access at {:?}, stack pointer {:?}", guard.name, addr, stack_pointer);
+    }
+}
 // handler for breakpoint exceptions
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
@@ -63,11 +92,55 @@ extern "x86-interrupt" fn page_fault_handler(
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    let addr = Cr2::read();
+    let not_present = !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    // kernel memory that appeared after the current address space was activated
+    if not_present && memory::address_space::sync_kernel_entry(addr) {
+        return;
+    }
+    // a missing page inside a lazily backed area just needs a frame
+    if not_present && memory::vma::handle_fault(addr, write) {
+        return;
+    }
+    // a write to a shared page gets its own copy
+    if !not_present && write && memory::cow::handle_fault(addr) {
+        return;
+    }
+    // user programs don't take the kernel down with them
+    if from_user(&stack_frame) {
+        usermode::leave(UserExit::PageFault(addr));
+    }
+    report_guard_fault(addr, stack_frame.stack_pointer);
+    let area = match memory::vma::find(addr) {
+        Some(area) => area.name,
+        None => "none",
+    };
+    panic!(
+        "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\nArea: {}\n{:#?}",
+        addr, error_code, area, stack_frame
+    );
+}
+// handler for general protection faults
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    if from_user(&stack_frame) {
+        usermode::leave(UserExit::GeneralProtection);
+    }
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT\nError Code: {:#x}\n{:#?}", error_code, stack_frame);
+}
+// handler for invalid opcodes
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    if from_user(&stack_frame) {
+        usermode::leave(UserExit::InvalidOpcode);
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+// whether the interrupted code ran in ring 3
+fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
 }
 use core::sync::atomic::{AtomicUsize, Ordering};
 lazy_static! {
@@ -94,6 +167,8 @@ extern "x86-interrupt" fn timer_interrupt_handler(
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    // the other threads get their turn, this one resumes here later
+    crate::thread::preempt();
 }
 // handler for keyboard interrupts
 extern "x86-interrupt" fn keyboard_interrupt_handler(
@@ -14,10 +14,17 @@ pub mod interrupts;
 pub mod allocator;
 pub mod input;
 pub mod task;
+pub mod thread;
+pub mod usermode;
+pub mod syscall;
+pub mod elf;
+pub mod programs;
+pub mod process;
 // initializes all kernel subsystems (gdt, idt, pics, etc)
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
+    syscall::init();
     unsafe { interrupts::PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
     vga_buffer::disable_cursor();
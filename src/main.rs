@@ -9,7 +9,6 @@ extern crate alloc;
 use toy_os::{println, print};
 use bootloader::{BootInfo, entry_point};
 use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
-use toy_os::memory::BootInfoFrameAllocator;
 use toy_os::{allocator, memory};
 entry_point!(kernel_main);
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
@@ -17,25 +16,34 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello World{}", "!");
     toy_os::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    // initialize page table mapper
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
+    // initialize page table mapper and frame allocator
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    // turn the bottom of the interrupt stacks into guard pages
+    toy_os::gdt::protect_stacks();
     
     // initialize the heap allocator
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
+    allocator::init_heap()
         .expect("heap initialization failed");
+    // from here on the timer switches threads, the executor runs as thread 0
+    toy_os::thread::init();
     #[cfg(test)]
     test_main();
     
     
-    use toy_os::task::{Task, executor::Executor};
+    use toy_os::task::{Priority, Task, executor::Executor};
     use toy_os::task::shell;
     use toy_os::task::status_bar;
     let mut executor = Executor::new();
-    executor.spawn(Task::new_named("status_bar", status_bar::run()));
-    executor.spawn(Task::new_named("shell", shell::run()));
+    // typing shouldn't have to wait for the spinner
+    executor
+        .spawn(Task::new_named("status_bar", Priority::Low, status_bar::run()))
+        .expect("no memory for the status bar task");
+    executor
+        .spawn(Task::new_named("shell", Priority::High, shell::run()))
+        .expect("no memory for the shell task");
     // run the task executor
     executor.run();
     println!("It did not crash!");
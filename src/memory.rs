@@ -3,10 +3,78 @@ use x86_64::{
     structures::paging::{PageTable, OffsetPageTable},
     VirtAddr,
 };
-// initializes the virtual memory system
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+// physical address of the level 4 table the kernel booted with
+static KERNEL_LEVEL_4: AtomicU64 = AtomicU64::new(0);
+// initializes the virtual memory system and installs the kernel mapper
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    use x86_64::instructions::interrupts;
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    protect::enable();
+    let (level_4_frame, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_LEVEL_4.store(level_4_frame.start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    let mapper = OffsetPageTable::new(level_4_table, physical_memory_offset);
+    interrupts::without_interrupts(|| *MAPPER.lock() = Some(mapper));
+    protect::enforce_w_xor_x();
+    vma::reserve_boot_mappings();
+}
+// runs `f` on the kernel page tables; `f` must not touch the heap, since
+// heap growth takes this lock while the heap allocator is locked
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        f(mapper.as_mut().expect("memory::init has not been called"))
+    })
+}
+// like `with_mapper` but gives up instead of spinning, for fault handlers
+// that may have interrupted a holder of the lock
+pub fn try_with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> Option<R> {
+    let mut mapper = MAPPER.try_lock()?;
+    Some(f(mapper.as_mut()?))
+}
+// runs `f` on the page tables rooted at `level_4`; the kernel half of every
+// address space is shared with the kernel's tables, so this holds the
+// mapper lock as well
+pub fn with_table<R>(level_4: PhysFrame, f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    with_mapper(|_| with_table_unlocked(level_4, f))
+}
+// like `with_table` for two address spaces at once
+pub fn with_tables<R>(
+    first: PhysFrame,
+    second: PhysFrame,
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut OffsetPageTable<'static>) -> R,
+) -> R {
+    with_table(first, |first| with_table_unlocked(second, |second| f(first, second)))
+}
+// like `with_table` but gives up instead of spinning, for fault handlers
+pub fn try_with_active_table<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> Option<R> {
+    use x86_64::registers::control::Cr3;
+    let _mapper = MAPPER.try_lock()?;
+    Some(with_table_unlocked(Cr3::read().0, f))
+}
+// the caller has to hold the mapper lock
+fn with_table_unlocked<R>(level_4: PhysFrame, f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    let table = unsafe { &mut *phys_to_virt(level_4.start_address()).as_mut_ptr::<PageTable>() };
+    let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+    f(&mut unsafe { OffsetPageTable::new(table, offset) })
+}
+// runs `f` on the page tables cr3 currently points at
+pub fn with_active_table<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    use x86_64::registers::control::Cr3;
+    with_table(Cr3::read().0, f)
+}
+// the frame of the level 4 table the kernel booted with
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(x86_64::PhysAddr::new(KERNEL_LEVEL_4.load(Ordering::Relaxed)))
+}
+// returns where a physical address is visible in the kernel's offset mapping
+pub fn phys_to_virt(addr: x86_64::PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
 }
 // returns a reference to the active level 4 page table
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
@@ -42,42 +110,73 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
         None
     }
 }
+pub mod frame_allocator;
+pub mod guard;
+pub mod vma;
+pub mod cow;
+pub mod huge;
+pub mod walker;
+pub mod regions;
+pub mod protect;
+pub mod address_space;
+pub use frame_allocator::{BitmapFrameAllocator, FrameStats};
+pub use regions::regions;
+pub use address_space::AddressSpace;
 use bootloader::bootinfo::MemoryMap;
-pub struct BootInfoFrameAllocator {
+use x86_64::structures::paging::{FrameDeallocator, PageSize};
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+// sets up the global frame allocator and returns a handle to it; unsafe for
+// the same reasons as `BitmapFrameAllocator::init`
+pub unsafe fn init_frame_allocator(
     memory_map: &'static MemoryMap,
-    next: usize,
-}
-impl BootInfoFrameAllocator {
-    // creates a frame allocator from the boot memory map
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
+    physical_memory_offset: VirtAddr,
+) -> GlobalFrameAllocator {
+    use x86_64::instructions::interrupts;
+    let allocator = BitmapFrameAllocator::init(memory_map, physical_memory_offset);
+    interrupts::without_interrupts(|| *FRAME_ALLOCATOR.lock() = Some(allocator));
+    regions::store(memory_map);
+    protect::protect_code_aliases();
+    GlobalFrameAllocator
+}
+// returns physical frame usage, or None before the allocator is set up; the
+// allocator lock is also taken by fault and syscall handlers, so interrupts
+// stay off while it is held
+pub fn frame_stats() -> Option<FrameStats> {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().as_ref().map(|a| a.stats()))
+}
+// zero-sized handle that forwards to the global frame allocator
+#[derive(Debug, Clone, Copy)]
+pub struct GlobalFrameAllocator;
+impl GlobalFrameAllocator {
+    fn with<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> R) -> R {
+        use x86_64::instructions::interrupts;
+        interrupts::without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            f(allocator.as_mut().expect("frame allocator not initialized"))
+        })
+    }
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        Self::with(|a| a.allocate_contiguous(count))
+    }
+    // unsafe for the same reasons as `BitmapFrameAllocator::deallocate_contiguous`
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        Self::with(|a| a.deallocate_contiguous(start, count))
+    }
+    pub fn share<S: PageSize>(&mut self, frame: PhysFrame<S>) {
+        Self::with(|a| a.share(frame))
+    }
+    pub fn references<S: PageSize>(&self, frame: PhysFrame<S>) -> usize {
+        Self::with(|a| a.references(frame))
     }
 }
-use bootloader::bootinfo::MemoryRegionType;
-use x86_64::PhysAddr;
-impl BootInfoFrameAllocator {
-    // returns an iterator over usable physical frames
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
-        
-        let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr());
-        
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+unsafe impl<S: PageSize> FrameAllocator<S> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        Self::with(|a| a.allocate_frame())
     }
 }
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+impl<S: PageSize> FrameDeallocator<S> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        Self::with(|a| a.deallocate_frame(frame))
     }
 }
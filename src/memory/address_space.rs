@@ -0,0 +1,197 @@
+// address spaces for user programs: a private level 4 table whose entries
+// in the user window belong to the program, every other entry points at
+// the kernel's own tables, so the kernel half is shared by all of them
+use super::cow;
+use super::vma::{self, Vma, VmaError, VmaKind, VmaManager, USER_WINDOW_END, USER_WINDOW_START};
+use super::GlobalFrameAllocator;
+use core::ops::Range;
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, PageTable,
+        PageTableFlags, PhysFrame,
+    },
+    VirtAddr,
+};
+// level 4 entries covering the user window
+const USER_ENTRIES: Range<usize> = (USER_WINDOW_START >> 39) as usize..(USER_WINDOW_END >> 39) as usize;
+pub struct AddressSpace {
+    level_4: PhysFrame,
+    areas: VmaManager,
+}
+impl AddressSpace {
+    // a new address space with an empty user window
+    pub fn new() -> Result<Self, VmaError> {
+        let level_4: PhysFrame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(VmaError::Map(MapToError::FrameAllocationFailed))?;
+        let used = super::with_mapper(|mapper| {
+            let table = table_at(level_4);
+            table.zero();
+            // boot mappings inside the user window are shared as well
+            for (index, entry) in mapper.level_4_table().iter().enumerate() {
+                table[index] = entry.clone();
+            }
+            vma::used_level_4_entries(mapper.level_4_table())
+        });
+        let mut areas = VmaManager::new(USER_WINDOW_START, USER_WINDOW_END);
+        areas.reserve_used(&used);
+        Ok(AddressSpace { level_4, areas })
+    }
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4
+    }
+    pub fn areas(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.iter()
+    }
+    // runs `f` on the page tables of this address space
+    pub fn with_table<R>(&self, f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+        super::with_table(self.level_4, f)
+    }
+    // reserves `size` bytes anywhere in the user window and maps them
+    // according to `kind`, `flags` should include USER_ACCESSIBLE
+    pub fn map(
+        &mut self,
+        name: &'static str,
+        size: u64,
+        flags: PageTableFlags,
+        kind: VmaKind,
+    ) -> Result<Vma, VmaError> {
+        let area = self.areas.reserve(name, size, flags, kind)?;
+        self.populate(area)
+    }
+    // like `map` but at a fixed address
+    pub fn map_at(
+        &mut self,
+        name: &'static str,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        kind: VmaKind,
+    ) -> Result<Vma, VmaError> {
+        let area = self.areas.reserve_at(name, start, size, flags, kind)?;
+        self.populate(area)
+    }
+    // like `vma::populate`, a failed `map_area` has cleaned up after itself
+    fn populate(&mut self, area: Vma) -> Result<Vma, VmaError> {
+        let mapped = self.with_table(|mapper| vma::map_area(mapper, &area));
+        if let Err(err) = mapped {
+            self.areas.remove(area.start);
+            return Err(err.into());
+        }
+        Ok(area)
+    }
+    // a copy of this address space whose pages are shared copy-on-write
+    pub fn fork(&self) -> Result<AddressSpace, VmaError> {
+        let mut child = AddressSpace::new()?;
+        // the boot entries are reserved by `new` already
+        let areas = || self.areas.iter().filter(|area| area.kind != VmaKind::Reserved);
+        for area in areas() {
+            child.areas.reserve_at(area.name, area.start, area.size(), area.flags, area.kind)?;
+        }
+        super::with_tables(self.level_4, child.level_4, |parent, target| {
+            areas().flat_map(Vma::pages).try_for_each(|page| match parent.translate_page(page) {
+                Ok(_) => cow::share_page_into(parent, target, page),
+                // guard pages
+                Err(_) => Ok(()),
+            })
+        })?;
+        Ok(child)
+    }
+    // unmaps the area starting at `start`, freeing frames it owns
+    pub fn unmap(&mut self, start: VirtAddr) -> Result<Vma, VmaError> {
+        let area = self.areas.remove(start).ok_or(VmaError::NotFound)?;
+        self.with_table(|mapper| vma::unmap_area(mapper, &area));
+        Ok(area)
+    }
+    // copies `bytes` into the address space, whatever the page permissions
+    pub fn write_bytes(&self, start: VirtAddr, bytes: &[u8]) -> Result<(), VmaError> {
+        self.with_table(|mapper| vma::write_bytes(mapper, start, bytes))
+    }
+    // pages backed by frames of this address space
+    pub fn mapped_pages(&self) -> u64 {
+        self.areas
+            .iter()
+            .map(|area| match area.kind {
+                VmaKind::Anonymous => area.size() / 4096,
+                VmaKind::Stack => area.size() / 4096 - 1,
+                _ => 0,
+            })
+            .sum()
+    }
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4
+    }
+    // switches cr3 to this address space, catching up on level 4 entries the
+    // kernel added since it was last active
+    pub fn activate(&self) {
+        super::with_mapper(|mapper| {
+            let table = table_at(self.level_4);
+            for (index, entry) in mapper.level_4_table().iter().enumerate() {
+                if !USER_ENTRIES.contains(&index) {
+                    table[index] = entry.clone();
+                }
+            }
+        });
+        unsafe { Cr3::write(self.level_4, Cr3Flags::empty()) };
+    }
+}
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        let areas = &self.areas;
+        super::with_table(self.level_4, |mapper| {
+            for area in areas.iter() {
+                vma::unmap_area(mapper, area);
+            }
+            let kernel = table_at(super::kernel_level_4_frame());
+            let table = mapper.level_4_table();
+            for index in USER_ENTRIES {
+                // anything the kernel table doesn't have is private
+                if !table[index].is_unused() && kernel[index].is_unused() {
+                    free_tables(table[index].frame().expect("huge level 4 entry"), 3);
+                    table[index].set_unused();
+                }
+            }
+        });
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.level_4) };
+    }
+}
+// switches cr3 back to the kernel's own tables
+pub fn activate_kernel() {
+    unsafe { Cr3::write(super::kernel_level_4_frame(), Cr3Flags::empty()) };
+}
+// copies a level 4 entry the kernel added after the active address space
+// was last activated, returns false if that isn't why `addr` faulted
+pub fn sync_kernel_entry(addr: VirtAddr) -> bool {
+    let index = usize::from(addr.p4_index());
+    let active = Cr3::read().0;
+    if USER_ENTRIES.contains(&index) || active == super::kernel_level_4_frame() {
+        return false;
+    }
+    super::try_with_mapper(|mapper| {
+        let kernel = &mapper.level_4_table()[index];
+        let table = table_at(active);
+        if kernel.is_unused() || !table[index].is_unused() {
+            return false;
+        }
+        table[index] = kernel.clone();
+        true
+    })
+    .unwrap_or(false)
+}
+// frees the page table at `frame` and the tables below it, the pages they
+// map have to be unmapped already
+fn free_tables(frame: PhysFrame, level: u8) {
+    if level > 1 {
+        for entry in table_at(frame).iter() {
+            if let Ok(child) = entry.frame() {
+                free_tables(child, level - 1);
+            }
+        }
+    }
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
+fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *super::phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() }
+}
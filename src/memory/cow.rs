@@ -0,0 +1,131 @@
+// copy-on-write: frames mapped read-only in several places, copied on the first write
+use super::vma::{self, Vma, VmaError};
+use super::GlobalFrameAllocator;
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags,
+        PhysFrame, Translate,
+    },
+    VirtAddr,
+};
+// software bit in the page table entry marking a page as copy-on-write
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+const PAGE_SIZE: usize = 4096;
+// maps the frame behind `source` at `target` too, both read-only until written
+pub fn share_page(
+    mapper: &mut OffsetPageTable<'static>,
+    source: Page,
+    target: Page,
+) -> Result<(), VmaError> {
+    let (frame, flags) = share_frame(mapper, source)?;
+    map_shared(mapper, target, frame, flags)
+}
+// like `share_page` with the same page in another address space
+pub fn share_page_into(
+    source: &mut OffsetPageTable<'static>,
+    target: &mut OffsetPageTable<'static>,
+    page: Page,
+) -> Result<(), VmaError> {
+    let (frame, flags) = share_frame(source, page)?;
+    map_shared(target, page, frame, flags)
+}
+// takes another reference to the frame behind `source`, returning it with
+// the flags every mapping of it has from now on
+fn share_frame(mapper: &mut OffsetPageTable<'static>, source: Page) -> Result<(PhysFrame, PageTableFlags), VmaError> {
+    let (frame, flags) = match mapper.translate(source.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+        TranslateResult::Mapped { .. } => return Err(VmaError::HugePage(source.start_address())),
+        _ => return Err(VmaError::NotMapped(source.start_address())),
+    };
+    // read-only pages never need a copy, only writable ones get the cow bit
+    let flags = if flags.contains(PageTableFlags::WRITABLE) {
+        let shared = (flags - PageTableFlags::WRITABLE) | COW;
+        let flush = unsafe { mapper.update_flags(source, shared) }
+            .map_err(|_| VmaError::NotMapped(source.start_address()))?;
+        flush.flush();
+        shared
+    } else {
+        flags
+    };
+    GlobalFrameAllocator.share(frame);
+    Ok((frame, flags))
+}
+fn map_shared(
+    mapper: &mut OffsetPageTable<'static>,
+    target: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), VmaError> {
+    match unsafe { mapper.map_to(target, frame, flags, &mut GlobalFrameAllocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            Err(err.into())
+        }
+    }
+}
+// clones a kernel area into a new one that shares all its frames
+// copy-on-write; areas that may use huge pages can't be shared
+pub fn snapshot(start: VirtAddr, name: &'static str) -> Result<Vma, VmaError> {
+    let source = vma::with_kernel_areas(|areas| areas.find(start)).ok_or(VmaError::NotFound)?;
+    if !source.kind.owns_frames() {
+        return Err(VmaError::NotFound);
+    }
+    if source.flags.contains(PageTableFlags::HUGE_PAGE) {
+        return Err(VmaError::HugePage(source.start));
+    }
+    let target = vma::with_kernel_areas(|areas| {
+        areas.reserve(name, source.size(), source.flags, source.kind)
+    })?;
+    let shared = super::with_mapper(|mapper| {
+        for (from, to) in source.pages().zip(target.pages()) {
+            if mapper.translate_page(from).is_ok() {
+                share_page(mapper, from, to)?;
+            }
+        }
+        Ok(())
+    });
+    if let Err(err) = shared {
+        unsafe { vma::unmap(target.start)? };
+        return Err(err);
+    }
+    Ok(target)
+}
+// resolves a write to a copy-on-write page of the active address space,
+// returns false if `addr` isn't one
+pub fn handle_fault(addr: VirtAddr) -> bool {
+    super::try_with_active_table(|mapper| resolve(mapper, Page::containing_address(addr)).is_some())
+        .unwrap_or(false)
+}
+fn resolve(mapper: &mut OffsetPageTable<'static>, page: Page) -> Option<()> {
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. }
+            if flags.contains(COW) => (frame, flags),
+        _ => return None,
+    };
+    let writable = (flags - COW) | PageTableFlags::WRITABLE;
+    // the last reference can simply take the page back
+    if GlobalFrameAllocator.references(frame) == 1 {
+        unsafe { mapper.update_flags(page, writable).ok()?.flush() };
+        return Some(());
+    }
+    let copy: PhysFrame = GlobalFrameAllocator.allocate_frame()?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            super::phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+            super::phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+            PAGE_SIZE,
+        );
+    }
+    let (_, flush) = mapper.unmap(page).ok()?;
+    flush.flush();
+    unsafe {
+        mapper.map_to(page, copy, writable, &mut GlobalFrameAllocator).ok()?.flush();
+        GlobalFrameAllocator.deallocate_frame(frame);
+    }
+    Some(())
+}
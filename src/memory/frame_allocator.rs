@@ -0,0 +1,241 @@
+// bitmap based physical frame allocator
+use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegionType};
+use core::slice;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = 64;
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total_frames: usize,
+    pub used_frames: usize,
+    pub free_frames: usize,
+}
+impl FrameStats {
+    pub fn total_bytes(&self) -> usize {
+        self.total_frames * FRAME_SIZE as usize
+    }
+    pub fn used_bytes(&self) -> usize {
+        self.used_frames * FRAME_SIZE as usize
+    }
+    pub fn free_bytes(&self) -> usize {
+        self.free_frames * FRAME_SIZE as usize
+    }
+}
+// one bit per 4 KiB frame, a set bit marks the frame as used
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // extra references to shared frames, freeing one only drops a reference
+    shares: &'static mut [u16],
+    frame_count: usize,
+    total_frames: usize,
+    free_frames: usize,
+    next_word: usize,
+    // every word below this one is fully used, runs are searched from here
+    first_free_word: usize,
+}
+impl BitmapFrameAllocator {
+    // builds the bitmap and share counts inside the first usable region that can hold them;
+    // the memory map must be valid, all physical memory mapped at
+    // `physical_memory_offset` and this called only once
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0) as usize;
+        let words = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_frames = (words * 8 + frame_count * 2).div_ceil(FRAME_SIZE as usize) as u64;
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .expect("no usable region large enough for the frame bitmap");
+        let bitmap_first_frame = bitmap_region.range.start_frame_number;
+        let bitmap_virt = physical_memory_offset + bitmap_region.range.start_addr();
+        let bitmap = unsafe { slice::from_raw_parts_mut(bitmap_virt.as_mut_ptr::<u64>(), words) };
+        bitmap.fill(u64::MAX);
+        let shares_virt = bitmap_virt + words as u64 * 8;
+        let shares = unsafe { slice::from_raw_parts_mut(shares_virt.as_mut_ptr::<u16>(), frame_count) };
+        shares.fill(0);
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            shares,
+            frame_count,
+            total_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+            first_free_word: 0,
+        };
+        for region in usable_regions() {
+            for frame in region.range.start_frame_number..region.range.end_frame_number {
+                allocator.mark_free(frame as usize);
+                allocator.total_frames += 1;
+            }
+        }
+        // the bitmap itself and the null frame are never handed out
+        for frame in bitmap_first_frame..bitmap_first_frame + bitmap_frames {
+            allocator.mark_used(frame as usize);
+        }
+        if frame_count > 0 {
+            allocator.mark_used(0);
+        }
+        allocator
+    }
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total_frames,
+            used_frames: self.total_frames - self.free_frames,
+            free_frames: self.free_frames,
+        }
+    }
+    // returns whether the frame at the given physical address is in use
+    pub fn is_used(&self, addr: PhysAddr) -> bool {
+        let index = (addr.as_u64() / FRAME_SIZE) as usize;
+        index >= self.frame_count || self.bit(index)
+    }
+    // adds a reference to an allocated frame, it is only freed once every
+    // reference has been deallocated. a huge frame counts on each of its 4 KiB
+    // frames, since deallocating it releases them one by one
+    pub fn share<S: PageSize>(&mut self, frame: PhysFrame<S>) {
+        let first = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        let frames = (S::SIZE / FRAME_SIZE) as usize;
+        assert!(
+            first + frames <= self.frame_count && (first..first + frames).all(|index| self.bit(index)),
+            "sharing a free frame"
+        );
+        for index in first..first + frames {
+            self.shares[index] = self.shares[index].checked_add(1).expect("too many frame references");
+        }
+    }
+    // returns how many references a frame has, 0 if it is free
+    pub fn references<S: PageSize>(&self, frame: PhysFrame<S>) -> usize {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        if index < self.frame_count && self.bit(index) {
+            self.shares[index] as usize + 1
+        } else {
+            0
+        }
+    }
+    // counts the frames of `range` that are in use
+    pub fn used_in(&self, range: FrameRange) -> usize {
+        let end = (range.end_frame_number as usize).min(self.frame_count);
+        (range.start_frame_number as usize..end).filter(|&index| self.bit(index)).count()
+    }
+    // allocates `count` physically contiguous 4 KiB frames
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let index = self.allocate_run(count, 1)?;
+        Some(frame_at(index))
+    }
+    // frees a run previously returned by `allocate_contiguous`, none of its
+    // frames may still be mapped or otherwise in use
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = (start.start_address().as_u64() / FRAME_SIZE) as usize;
+        for index in first..first + count {
+            self.release(index);
+        }
+    }
+    fn bit(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+    fn mark_used(&mut self, index: usize) {
+        if !self.bit(index) {
+            self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+            self.free_frames -= 1;
+        }
+    }
+    fn mark_free(&mut self, index: usize) {
+        if self.bit(index) {
+            self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+            self.free_frames += 1;
+        }
+    }
+    fn release(&mut self, index: usize) {
+        assert!(
+            index < self.frame_count && self.bit(index),
+            "physical frame {:#x} freed twice or never allocated",
+            index as u64 * FRAME_SIZE
+        );
+        if self.shares[index] > 0 {
+            self.shares[index] -= 1;
+            return;
+        }
+        self.mark_free(index);
+        self.next_word = self.next_word.min(index / BITS_PER_WORD);
+        self.first_free_word = self.first_free_word.min(index / BITS_PER_WORD);
+    }
+    // finds a single free frame, skipping full words of the bitmap
+    fn allocate_one(&mut self) -> Option<usize> {
+        let words = self.bitmap.len();
+        for offset in 0..words {
+            let word_index = (self.next_word + offset) % words;
+            let word = self.bitmap[word_index];
+            if word == u64::MAX {
+                continue;
+            }
+            let index = word_index * BITS_PER_WORD + word.trailing_ones() as usize;
+            if index >= self.frame_count {
+                continue;
+            }
+            self.mark_used(index);
+            self.next_word = word_index;
+            return Some(index);
+        }
+        None
+    }
+    // finds `count` free frames in a row whose first index is a multiple of `align`
+    fn allocate_run(&mut self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+        if count == 1 && align == 1 {
+            return self.allocate_one();
+        }
+        let words = self.bitmap.len();
+        while self.first_free_word < words && self.bitmap[self.first_free_word] == u64::MAX {
+            self.first_free_word += 1;
+        }
+        let mut start = (self.first_free_word * BITS_PER_WORD).next_multiple_of(align);
+        while start + count <= self.frame_count {
+            // a fully used word can't hold the start of a run
+            let word = start / BITS_PER_WORD;
+            if self.bitmap[word] == u64::MAX {
+                start = ((word + 1) * BITS_PER_WORD).next_multiple_of(align);
+                continue;
+            }
+            match (start..start + count).rev().find(|&i| self.bit(i)) {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    for index in start..start + count {
+                        self.mark_used(index);
+                    }
+                    return Some(start);
+                }
+            }
+        }
+        None
+    }
+}
+fn frame_at<S: PageSize>(index: usize) -> PhysFrame<S> {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+unsafe impl<S: PageSize> FrameAllocator<S> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let frames = (S::SIZE / FRAME_SIZE) as usize;
+        let index = self.allocate_run(frames, frames)?;
+        Some(frame_at(index))
+    }
+}
+impl<S: PageSize> FrameDeallocator<S> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let frames = (S::SIZE / FRAME_SIZE) as usize;
+        let first = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        for index in first..first + frames {
+            self.release(index);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+// guard pages around kernel stacks and lookup of faults that hit them
+use super::vma::{self, VmaError, VmaKind};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+const PAGE_SIZE: u64 = 4096;
+const MAX_GUARDS: usize = 64;
+#[derive(Debug, Clone, Copy)]
+pub struct GuardRegion {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+}
+// fixed size so that fault handlers can search it without touching the heap
+static GUARDS: Mutex<[Option<GuardRegion>; MAX_GUARDS]> = Mutex::new([None; MAX_GUARDS]);
+// records an unmapped range so faults inside it can be reported by name
+pub fn register(name: &'static str, start: VirtAddr, end: VirtAddr) -> bool {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut guards = GUARDS.lock();
+        match guards.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(GuardRegion { name, start, end });
+                true
+            }
+            None => false,
+        }
+    })
+}
+pub fn unregister(start: VirtAddr) {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        for slot in GUARDS.lock().iter_mut() {
+            if slot.is_some_and(|guard| guard.start == start) {
+                *slot = None;
+            }
+        }
+    });
+}
+// returns the guard region containing `addr`, safe to call from fault handlers
+pub fn find(addr: VirtAddr) -> Option<GuardRegion> {
+    let guards = GUARDS.try_lock()?;
+    guards
+        .iter()
+        .flatten()
+        .find(|guard| guard.start <= addr && addr < guard.end)
+        .copied()
+}
+#[derive(Debug)]
+pub struct KernelStack {
+    pub name: &'static str,
+    pub guard: VirtAddr,
+    pub bottom: VirtAddr,
+    pub top: VirtAddr,
+}
+// maps a fresh kernel stack of `pages` pages with a guard page below it
+pub fn alloc_stack(name: &'static str, pages: u64) -> Result<KernelStack, VmaError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let area = vma::map(name, (pages + 1) * PAGE_SIZE, flags, VmaKind::Stack)?;
+    let bottom = area.start + PAGE_SIZE;
+    register(name, area.start, bottom);
+    Ok(KernelStack {
+        name,
+        guard: area.start,
+        bottom,
+        top: area.end,
+    })
+}
+// unmaps a stack from `alloc_stack` and returns its frames, nothing may run
+// on the stack or hold references into it anymore
+pub unsafe fn free_stack(stack: KernelStack) {
+    unregister(stack.guard);
+    unsafe { vma::unmap(stack.guard).expect("kernel stack is not mapped") };
+}
+// turns the lowest page of a statically allocated stack into a guard page;
+// `bottom` must be the lowest address of a stack that never grows into that
+// page, since it gets unmapped
+pub unsafe fn protect_static_stack(name: &'static str, bottom: VirtAddr) {
+    let page = Page::<Size4KiB>::containing_address(bottom);
+    let unmapped = super::with_mapper(|mapper| match mapper.unmap(page) {
+        Ok((_, flush)) => {
+            flush.flush();
+            true
+        }
+        Err(_) => false,
+    });
+    if unmapped {
+        register(name, page.start_address(), page.start_address() + PAGE_SIZE);
+    }
+}
@@ -0,0 +1,193 @@
+// mapping ranges with the largest page size that fits
+use super::GlobalFrameAllocator;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSizeKind {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+impl PageSizeKind {
+    pub fn bytes(self) -> u64 {
+        match self {
+            PageSizeKind::Size4KiB => Size4KiB::SIZE,
+            PageSizeKind::Size2MiB => Size2MiB::SIZE,
+            PageSizeKind::Size1GiB => Size1GiB::SIZE,
+        }
+    }
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PageSizeKind::Size4KiB => "4K",
+            PageSizeKind::Size2MiB => "2M",
+            PageSizeKind::Size1GiB => "1G",
+        }
+    }
+    // the largest page size this cpu can map
+    pub fn largest_supported() -> Self {
+        if gigabyte_pages_supported() {
+            PageSizeKind::Size1GiB
+        } else {
+            PageSizeKind::Size2MiB
+        }
+    }
+}
+// whether the cpu supports 1 GiB pages (cpuid 0x8000_0001, edx bit 26)
+pub fn gigabyte_pages_supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+    let max_extended = __cpuid(0x8000_0000).eax;
+    max_extended >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+}
+// picks the largest size up to `largest` whose alignment `virt` (and `phys`)
+// satisfy and that doesn't run past the `remaining` bytes
+fn best_size(virt: VirtAddr, phys: Option<PhysAddr>, remaining: u64, largest: PageSizeKind) -> PageSizeKind {
+    let fits = |size: PageSizeKind| {
+        size <= largest
+            && remaining >= size.bytes()
+            && virt.is_aligned(size.bytes())
+            && phys.is_none_or(|phys| phys.is_aligned(size.bytes()))
+    };
+    [PageSizeKind::Size1GiB, PageSizeKind::Size2MiB]
+        .into_iter()
+        .find(|&size| fits(size))
+        .unwrap_or(PageSizeKind::Size4KiB)
+}
+// maps [start, start + size) with fresh zeroed frames, using pages up to
+// `largest`; on failure the pages mapped so far are unmapped and freed again
+pub fn map_zeroed_range(
+    mapper: &mut OffsetPageTable<'static>,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    largest: PageSizeKind,
+) -> Result<(), MapToError<Size4KiB>> {
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        let page_size = best_size(addr, None, end - addr, largest);
+        let mapped = match page_size {
+            PageSizeKind::Size4KiB => map_zeroed::<Size4KiB>(mapper, addr, flags),
+            PageSizeKind::Size2MiB => map_zeroed::<Size2MiB>(mapper, addr, flags).map_err(narrow),
+            PageSizeKind::Size1GiB => map_zeroed::<Size1GiB>(mapper, addr, flags).map_err(narrow),
+        };
+        if let Err(err) = mapped {
+            unmap_range(mapper, start, addr - start, true);
+            return Err(err);
+        }
+        addr += page_size.bytes();
+    }
+    Ok(())
+}
+// maps [start, start + size) onto the physical range starting at `phys`,
+// using pages up to `largest`; on failure the pages mapped so far are unmapped
+pub fn map_physical_range(
+    mapper: &mut OffsetPageTable<'static>,
+    start: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    largest: PageSizeKind,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut offset = 0;
+    while offset < size {
+        let (addr, frame) = (start + offset, phys + offset);
+        let page_size = best_size(addr, Some(frame), size - offset, largest);
+        let mapped = match page_size {
+            PageSizeKind::Size4KiB => map_frame::<Size4KiB>(mapper, addr, frame, flags),
+            PageSizeKind::Size2MiB => map_frame::<Size2MiB>(mapper, addr, frame, flags).map_err(narrow),
+            PageSizeKind::Size1GiB => map_frame::<Size1GiB>(mapper, addr, frame, flags).map_err(narrow),
+        };
+        if let Err(err) = mapped {
+            unmap_range(mapper, start, offset, false);
+            return Err(err);
+        }
+        offset += page_size.bytes();
+    }
+    Ok(())
+}
+// unmaps whatever is mapped in [start, start + size), whatever its page size,
+// and hands the frames back if `free_frames` is set
+pub fn unmap_range(mapper: &mut OffsetPageTable<'static>, start: VirtAddr, size: u64, free_frames: bool) {
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        let mapped = match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => frame,
+            _ => {
+                addr += Size4KiB::SIZE;
+                continue;
+            }
+        };
+        match mapped {
+            MappedFrame::Size4KiB(_) => unmap_page::<Size4KiB>(mapper, addr, free_frames),
+            MappedFrame::Size2MiB(_) => unmap_page::<Size2MiB>(mapper, addr, free_frames),
+            MappedFrame::Size1GiB(_) => unmap_page::<Size1GiB>(mapper, addr, free_frames),
+        }
+        addr = addr.align_down(mapped.size()) + mapped.size();
+    }
+}
+fn map_zeroed<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    addr: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<S>>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+    GlobalFrameAllocator: FrameAllocator<S>,
+{
+    let frame: PhysFrame<S> = GlobalFrameAllocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let virt = super::phys_to_virt(frame.start_address());
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, S::SIZE as usize) };
+    let result = map_frame(mapper, addr, frame.start_address(), flags);
+    if result.is_err() {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
+    result
+}
+fn map_frame<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    addr: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<S>>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let page = Page::<S>::from_start_address(addr).expect("unaligned page");
+    let frame = PhysFrame::<S>::from_start_address(phys).expect("unaligned frame");
+    // the mapper sets HUGE_PAGE itself where it belongs
+    let flags = flags - PageTableFlags::HUGE_PAGE;
+    unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator)?.flush() };
+    Ok(())
+}
+fn unmap_page<S: PageSize>(mapper: &mut OffsetPageTable<'static>, addr: VirtAddr, free_frame: bool)
+where
+    OffsetPageTable<'static>: Mapper<S>,
+    GlobalFrameAllocator: FrameDeallocator<S>,
+{
+    let page = Page::<S>::containing_address(addr);
+    if let Ok((frame, flush)) = mapper.unmap(page) {
+        flush.flush();
+        if free_frame {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+// page table frames are always 4 KiB, so only the mapped frame differs
+fn narrow<S: PageSize>(err: MapToError<S>) -> MapToError<Size4KiB> {
+    match err {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    }
+}
@@ -0,0 +1,121 @@
+// hardware protection for kernel mappings: NX, write protection and W^X
+use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        page_table::PageTableEntry, FrameAllocator, PageTableIndex, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr,
+};
+// makes NO_EXECUTE take effect and read-only pages apply to ring 0 as well,
+// which copy-on-write depends on
+pub(super) fn enable() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+// returns whether NX and write protection are both switched on
+pub fn enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+        && Cr0::read().contains(Cr0Flags::WRITE_PROTECT)
+}
+// marks every writable page that is still executable as NO_EXECUTE, so no
+// page can be both written and run; code keeps the read-only mapping the
+// bootloader gave it. returns how many entries were changed. the code frames
+// stay writable through the physical memory mapping until
+// `protect_code_aliases` runs
+pub(super) fn enforce_w_xor_x() -> usize {
+    let changed = super::with_mapper(|mapper| harden(mapper.level_4_table(), 4));
+    tlb::flush_all();
+    changed
+}
+// the physical memory mapping is a second, writable way to reach the kernel
+// code, which would undo W^X; makes the code frames read-only there as well,
+// splitting the 2 MiB pages of that mapping where needed. needs the frame
+// allocator, returns how many code frames were protected
+pub(super) fn protect_code_aliases() -> usize {
+    let protected = super::with_mapper(|mapper| {
+        let root: *mut PageTable = mapper.level_4_table();
+        let mut protected = 0;
+        for_each_code_frame(unsafe { &*root }, 4, &mut |frame| {
+            if protect_alias(unsafe { &mut *root }, frame) {
+                protected += 1;
+            }
+        });
+        protected
+    });
+    tlb::flush_all();
+    protected
+}
+// code is what the bootloader mapped neither writable nor NO_EXECUTE
+fn for_each_code_frame(table: &PageTable, level: u8, f: &mut impl FnMut(PhysAddr)) {
+    for entry in table.iter() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if level > 1 && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            let next = super::phys_to_virt(entry.addr());
+            for_each_code_frame(unsafe { &*next.as_ptr::<PageTable>() }, level - 1, f);
+        } else if level == 1 && !flags.intersects(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE) {
+            f(entry.addr());
+        }
+    }
+}
+// clears WRITABLE on the entry mapping `frame` in the physical memory mapping
+fn protect_alias(root: &mut PageTable, frame: PhysAddr) -> bool {
+    let alias = super::phys_to_virt(frame);
+    let next = |table: &mut PageTable, index: PageTableIndex| -> Option<&'static mut PageTable> {
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        Some(unsafe { &mut *super::phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>() })
+    };
+    let Some(level_3) = next(root, alias.p4_index()) else { return false };
+    let Some(level_2) = next(level_3, alias.p3_index()) else { return false };
+    let entry = &mut level_2[alias.p2_index()];
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) && !split(entry) {
+        return false;
+    }
+    let Some(level_1) = next(level_2, alias.p2_index()) else { return false };
+    let entry = &mut level_1[alias.p1_index()];
+    entry.set_flags(entry.flags() - PageTableFlags::WRITABLE);
+    true
+}
+// replaces a 2 MiB entry by a table of 512 4 KiB entries with the same flags
+fn split(entry: &mut PageTableEntry) -> bool {
+    let frame: Option<PhysFrame<Size4KiB>> = super::GlobalFrameAllocator.allocate_frame();
+    let Some(table_frame) = frame else { return false };
+    let flags = entry.flags() - PageTableFlags::HUGE_PAGE;
+    let base = entry.addr();
+    let table = unsafe { &mut *super::phys_to_virt(table_frame.start_address()).as_mut_ptr::<PageTable>() };
+    for (index, small) in table.iter_mut().enumerate() {
+        small.set_addr(base + index as u64 * 4096, flags);
+    }
+    entry.set_addr(table_frame.start_address(), flags);
+    true
+}
+fn harden(table: &mut PageTable, level: u8) -> usize {
+    let mut changed = 0;
+    for entry in table.iter_mut() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let leaf = level == 1 || (level <= 3 && flags.contains(PageTableFlags::HUGE_PAGE));
+        if !leaf {
+            let next = super::phys_to_virt(entry.addr());
+            changed += harden(unsafe { &mut *next.as_mut_ptr::<PageTable>() }, level - 1);
+        } else if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE) {
+            entry.set_flags(flags | PageTableFlags::NO_EXECUTE);
+            changed += 1;
+        }
+    }
+    changed
+}
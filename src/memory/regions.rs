@@ -0,0 +1,85 @@
+// the physical memory map handed over by the bootloader
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
+use spin::Mutex;
+static MEMORY_MAP: Mutex<Option<&'static MemoryMap>> = Mutex::new(None);
+pub(super) fn store(memory_map: &'static MemoryMap) {
+    *MEMORY_MAP.lock() = Some(memory_map);
+}
+// returns the regions of the memory map, empty before the frame allocator is set up
+pub fn regions() -> &'static [MemoryRegion] {
+    let memory_map: Option<&'static MemoryMap> = *MEMORY_MAP.lock();
+    match memory_map {
+        Some(memory_map) => &memory_map[..],
+        None => &[],
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionClass {
+    Usable,
+    // the kernel image, its stack and page tables, and what the bootloader left
+    Kernel,
+    Reserved,
+}
+pub fn region_class(region: &MemoryRegion) -> RegionClass {
+    match region.region_type {
+        MemoryRegionType::Usable => RegionClass::Usable,
+        MemoryRegionType::Kernel
+        | MemoryRegionType::KernelStack
+        | MemoryRegionType::PageTable
+        | MemoryRegionType::Bootloader
+        | MemoryRegionType::BootInfo
+        | MemoryRegionType::Package => RegionClass::Kernel,
+        _ => RegionClass::Reserved,
+    }
+}
+pub fn region_type_name(region_type: MemoryRegionType) -> &'static str {
+    match region_type {
+        MemoryRegionType::Usable => "usable",
+        MemoryRegionType::InUse => "in use",
+        MemoryRegionType::Reserved => "reserved",
+        MemoryRegionType::AcpiReclaimable => "acpi reclaim",
+        MemoryRegionType::AcpiNvs => "acpi nvs",
+        MemoryRegionType::BadMemory => "bad memory",
+        MemoryRegionType::Kernel => "kernel",
+        MemoryRegionType::KernelStack => "kernel stack",
+        MemoryRegionType::PageTable => "page table",
+        MemoryRegionType::Bootloader => "bootloader",
+        MemoryRegionType::FrameZero => "frame zero",
+        MemoryRegionType::Empty => "empty",
+        MemoryRegionType::BootInfo => "boot info",
+        MemoryRegionType::Package => "package",
+        _ => "unknown",
+    }
+}
+pub fn region_size(region: &MemoryRegion) -> u64 {
+    region.range.end_addr() - region.range.start_addr()
+}
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RegionTotals {
+    pub usable: u64,
+    pub kernel: u64,
+    pub reserved: u64,
+}
+// sums the region sizes per class, in bytes
+pub fn region_totals() -> RegionTotals {
+    let mut totals = RegionTotals::default();
+    for region in regions() {
+        let size = region_size(region);
+        match region_class(region) {
+            RegionClass::Usable => totals.usable += size,
+            RegionClass::Kernel => totals.kernel += size,
+            RegionClass::Reserved => totals.reserved += size,
+        }
+    }
+    totals
+}
+// returns how many frames of `region` the frame allocator has handed out
+pub fn used_frames_in(region: &MemoryRegion) -> usize {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        super::FRAME_ALLOCATOR
+            .lock()
+            .as_ref()
+            .map_or(0, |allocator| allocator.used_in(region.range))
+    })
+}
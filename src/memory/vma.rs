@@ -0,0 +1,329 @@
+// virtual memory areas: non-overlapping ranges of an address space together
+// with their permissions and what backs them
+use super::huge::{self, PageSizeKind};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, page::PageRange, OffsetPageTable, Page, PageTable, PageTableFlags,
+        Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+const PAGE_SIZE: u64 = 4096;
+// fixed capacity so that the table never needs the heap
+pub const MAX_AREAS: usize = 128;
+// part of the address space the kernel hands out ranges from
+pub const KERNEL_WINDOW_START: u64 = 0x_4000_0000_0000;
+pub const KERNEL_WINDOW_END: u64 = 0x_7000_0000_0000;
+// part of the lower half that user programs are loaded into
+pub const USER_WINDOW_START: u64 = 0x_1000_0000_0000;
+pub const USER_WINDOW_END: u64 = 0x_4000_0000_0000;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    // only reserved, the owner maps and unmaps the pages itself
+    Reserved,
+    // fresh zeroed frames, freed again on unmap
+    Anonymous,
+    // like Anonymous, but the lowest page stays unmapped as a guard page
+    Stack,
+    // zeroed frames mapped on first access by the page fault handler
+    Lazy,
+    // device memory starting at the given physical address, mapped uncached
+    // and never executable
+    Mmio(PhysAddr),
+}
+impl VmaKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VmaKind::Reserved => "reserved",
+            VmaKind::Anonymous => "anonymous",
+            VmaKind::Stack => "stack",
+            VmaKind::Lazy => "lazy",
+            VmaKind::Mmio(_) => "mmio",
+        }
+    }
+    // whether unmapping the area frees its frames
+    pub fn owns_frames(self) -> bool {
+        matches!(self, VmaKind::Anonymous | VmaKind::Stack | VmaKind::Lazy)
+    }
+}
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+    pub kind: VmaKind,
+}
+impl Vma {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+    pub fn pages(&self) -> PageRange<Size4KiB> {
+        Page::range(Page::containing_address(self.start), Page::containing_address(self.end))
+    }
+}
+#[derive(Debug)]
+pub enum VmaError {
+    OutOfVirtualSpace,
+    OutsideWindow,
+    Overlap(Vma),
+    TooManyAreas,
+    NotFound,
+    NotMapped(VirtAddr),
+    // copy-on-write only shares 4 KiB pages
+    HugePage(VirtAddr),
+    Map(MapToError<Size4KiB>),
+}
+impl From<MapToError<Size4KiB>> for VmaError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        VmaError::Map(err)
+    }
+}
+// the areas of one address space, kept sorted by start address
+pub struct VmaManager {
+    window_start: u64,
+    window_end: u64,
+    areas: [Option<Vma>; MAX_AREAS],
+    len: usize,
+}
+impl VmaManager {
+    // manages the page aligned window [start, end)
+    pub const fn new(window_start: u64, window_end: u64) -> Self {
+        VmaManager {
+            window_start,
+            window_end,
+            areas: [None; MAX_AREAS],
+            len: 0,
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas[..self.len].iter().flatten()
+    }
+    pub fn find(&self, addr: VirtAddr) -> Option<Vma> {
+        self.iter().find(|area| area.contains(addr)).copied()
+    }
+    // reserves [start, start + size), failing if it overlaps an existing area
+    pub fn reserve_at(
+        &mut self,
+        name: &'static str,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        kind: VmaKind,
+    ) -> Result<Vma, VmaError> {
+        assert!(start.is_aligned(PAGE_SIZE), "areas must be page aligned");
+        let size = align_up(size, PAGE_SIZE);
+        let end = start.as_u64().checked_add(size).ok_or(VmaError::OutsideWindow)?;
+        if start.as_u64() < self.window_start || end > self.window_end || size == 0 {
+            return Err(VmaError::OutsideWindow);
+        }
+        if let Some(area) = self.iter().find(|a| a.start.as_u64() < end && start < a.end) {
+            return Err(VmaError::Overlap(*area));
+        }
+        let area = Vma { name, start, end: VirtAddr::new(end), flags, kind };
+        self.insert(area)?;
+        Ok(area)
+    }
+    // reserves `size` bytes at the lowest free address of the window
+    pub fn reserve(
+        &mut self,
+        name: &'static str,
+        size: u64,
+        flags: PageTableFlags,
+        kind: VmaKind,
+    ) -> Result<Vma, VmaError> {
+        let size = align_up(size, PAGE_SIZE);
+        // areas that may use huge pages start on a huge page boundary
+        let align = match flags.contains(PageTableFlags::HUGE_PAGE) {
+            true => [PageSizeKind::Size1GiB, PageSizeKind::Size2MiB]
+                .into_iter()
+                .filter(|&kind| kind <= PageSizeKind::largest_supported())
+                .map(PageSizeKind::bytes)
+                .find(|&bytes| size >= bytes)
+                .unwrap_or(PAGE_SIZE),
+            false => PAGE_SIZE,
+        };
+        let mut candidate = align_up(self.window_start, align);
+        for area in self.iter() {
+            if area.start.as_u64() >= candidate + size {
+                break;
+            }
+            candidate = align_up(candidate.max(area.end.as_u64()), align);
+        }
+        if candidate + size > self.window_end {
+            return Err(VmaError::OutOfVirtualSpace);
+        }
+        self.reserve_at(name, VirtAddr::new(candidate), size, flags, kind)
+    }
+    // drops the area starting at `start` from the table and returns it
+    pub fn remove(&mut self, start: VirtAddr) -> Option<Vma> {
+        let index = self.areas[..self.len].iter().position(|a| a.is_some_and(|a| a.start == start))?;
+        let area = self.areas[index].take();
+        self.areas[index..self.len].rotate_left(1);
+        self.len -= 1;
+        area
+    }
+    // reserves the used level 4 entries inside the window as "boot" areas
+    pub fn reserve_used(&mut self, used: &[bool; 512]) {
+        let first = (self.window_start >> 39) as usize;
+        let last = (self.window_end >> 39) as usize;
+        for index in (first..last).filter(|&index| used[index]) {
+            let start = VirtAddr::new((index as u64) << 39);
+            self.reserve_at("boot", start, 1 << 39, PageTableFlags::PRESENT, VmaKind::Reserved)
+                .expect("overlapping boot mappings");
+        }
+    }
+    fn insert(&mut self, area: Vma) -> Result<(), VmaError> {
+        if self.len == MAX_AREAS {
+            return Err(VmaError::TooManyAreas);
+        }
+        let index = self.iter().take_while(|a| a.start < area.start).count();
+        self.areas[index..=self.len].rotate_right(1);
+        self.areas[index] = Some(area);
+        self.len += 1;
+        Ok(())
+    }
+}
+static KERNEL_AREAS: Mutex<VmaManager> =
+    Mutex::new(VmaManager::new(KERNEL_WINDOW_START, KERNEL_WINDOW_END));
+// runs `f` on the kernel areas, taken before the mapper lock and never
+// while the heap allocator is locked
+pub fn with_kernel_areas<R>(f: impl FnOnce(&mut VmaManager) -> R) -> R {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| f(&mut KERNEL_AREAS.lock()))
+}
+// returns the kernel area containing `addr`, safe to call from fault handlers
+pub fn find(addr: VirtAddr) -> Option<Vma> {
+    KERNEL_AREAS.try_lock()?.find(addr)
+}
+// marks level 4 entries of the kernel window that the bootloader already
+// uses, so nothing gets placed on top of them
+pub(super) fn reserve_boot_mappings() {
+    let used = super::with_mapper(|mapper| used_level_4_entries(mapper.level_4_table()));
+    with_kernel_areas(|areas| areas.reserve_used(&used));
+}
+// which entries of a level 4 table are in use
+pub fn used_level_4_entries(table: &PageTable) -> [bool; 512] {
+    let mut used = [false; 512];
+    for (index, entry) in table.iter().enumerate() {
+        used[index] = !entry.is_unused();
+    }
+    used
+}
+// reserves a kernel area anywhere in the window and maps it according to `kind`
+pub fn map(
+    name: &'static str,
+    size: u64,
+    flags: PageTableFlags,
+    kind: VmaKind,
+) -> Result<Vma, VmaError> {
+    let area = with_kernel_areas(|areas| areas.reserve(name, size, flags, kind))?;
+    populate(area)
+}
+// like `map` but at a fixed address
+pub fn map_at(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    kind: VmaKind,
+) -> Result<Vma, VmaError> {
+    let area = with_kernel_areas(|areas| areas.reserve_at(name, start, size, flags, kind))?;
+    populate(area)
+}
+// `map_area` undoes its own partial work, so a failure only drops the
+// reservation; unmapping the area would also take down whatever mapping
+// made it fail
+fn populate(area: Vma) -> Result<Vma, VmaError> {
+    let mapped = super::with_mapper(|mapper| map_area(mapper, &area));
+    if let Err(err) = mapped {
+        with_kernel_areas(|areas| areas.remove(area.start));
+        return Err(err.into());
+    }
+    Ok(area)
+}
+// unmaps the kernel area starting at `start`, freeing frames it owns;
+// nothing may access the area or hold references into it anymore
+pub unsafe fn unmap(start: VirtAddr) -> Result<Vma, VmaError> {
+    let area = with_kernel_areas(|areas| areas.remove(start)).ok_or(VmaError::NotFound)?;
+    super::with_mapper(|mapper| unmap_area(mapper, &area));
+    Ok(area)
+}
+// copies `bytes` to `start` through the physical memory mapping, so it also
+// works for pages that are read-only where they are mapped
+pub fn write_bytes(
+    mapper: &OffsetPageTable<'static>,
+    start: VirtAddr,
+    bytes: &[u8],
+) -> Result<(), VmaError> {
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let addr = start + offset as u64;
+        let phys = mapper.translate_addr(addr).ok_or(VmaError::NotMapped(addr))?;
+        // stop at the end of the page, the next one may live anywhere
+        let chunk = (PAGE_SIZE - addr.as_u64() % PAGE_SIZE).min((bytes.len() - offset) as u64) as usize;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes[offset..].as_ptr(),
+                super::phys_to_virt(phys).as_mut_ptr::<u8>(),
+                chunk,
+            );
+        }
+        offset += chunk;
+    }
+    Ok(())
+}
+// maps the pages of `area` in any address space and unmaps them again if
+// that fails partway; HUGE_PAGE in the area flags lets aligned parts of it
+// use 2 MiB or 1 GiB pages, it is only a hint kept in the area and page table
+// entries get it from the page size actually used
+pub fn map_area(
+    mapper: &mut OffsetPageTable<'static>,
+    area: &Vma,
+) -> Result<(), MapToError<Size4KiB>> {
+    let largest = match area.flags.contains(PageTableFlags::HUGE_PAGE) {
+        true => PageSizeKind::largest_supported(),
+        false => PageSizeKind::Size4KiB,
+    };
+    match area.kind {
+        VmaKind::Reserved | VmaKind::Lazy => Ok(()),
+        VmaKind::Anonymous => huge::map_zeroed_range(mapper, area.start, area.size(), area.flags, largest),
+        VmaKind::Stack => {
+            let bottom = area.start + PAGE_SIZE;
+            huge::map_zeroed_range(mapper, bottom, area.end - bottom, area.flags, largest)
+        }
+        VmaKind::Mmio(phys) => {
+            let flags = area.flags | PageTableFlags::NO_CACHE | PageTableFlags::NO_EXECUTE;
+            huge::map_physical_range(mapper, area.start, phys, area.size(), flags, largest)
+        }
+    }
+}
+// resolves a fault on a not yet backed page of a lazy kernel area,
+// returns false if the access isn't allowed there
+pub fn handle_fault(addr: VirtAddr, write: bool) -> bool {
+    let area = match find(addr) {
+        Some(area) if area.kind == VmaKind::Lazy => area,
+        _ => return false,
+    };
+    if write && !area.flags.contains(PageTableFlags::WRITABLE) {
+        return false;
+    }
+    let page = addr.align_down(PAGE_SIZE);
+    super::try_with_mapper(|mapper| {
+        huge::map_zeroed_range(mapper, page, PAGE_SIZE, area.flags, PageSizeKind::Size4KiB).is_ok()
+    })
+    .unwrap_or(false)
+}
+// unmaps whatever pages of `area` are mapped, freeing frames the area owns
+pub fn unmap_area(mapper: &mut OffsetPageTable<'static>, area: &Vma) {
+    if area.kind != VmaKind::Reserved {
+        huge::unmap_range(mapper, area.start, area.size(), area.kind.owns_frames());
+    }
+}
+const fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,113 @@
+// page table walker that reports what is mapped where
+use super::huge::PageSizeKind;
+use x86_64::{
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+// a run of pages with the same flags and page size whose virtual and
+// physical addresses are both contiguous
+#[derive(Debug, Clone, Copy)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub phys: PhysAddr,
+    pub flags: PageTableFlags,
+    pub page_size: PageSizeKind,
+}
+impl MappedRange {
+    pub fn pages(&self) -> u64 {
+        self.size / self.page_size.bytes()
+    }
+    fn extend(&mut self, next: &MappedRange) -> bool {
+        let contiguous = self.start.as_u64().wrapping_add(self.size) == next.start.as_u64()
+            && self.phys + self.size == next.phys;
+        if contiguous && self.flags == next.flags && self.page_size == next.page_size {
+            self.size += next.size;
+            true
+        } else {
+            false
+        }
+    }
+    // permission string like `rw-u`, combining every level of the walk
+    pub fn permissions(&self) -> &'static str {
+        const PERMISSIONS: [&str; 8] = ["r---", "rw--", "r-x-", "rwx-", "r--u", "rw-u", "r-xu", "rwxu"];
+        let writable = self.flags.contains(PageTableFlags::WRITABLE) as usize;
+        let executable = !self.flags.contains(PageTableFlags::NO_EXECUTE) as usize;
+        let user = self.flags.contains(PageTableFlags::USER_ACCESSIBLE) as usize;
+        PERMISSIONS[writable | executable << 1 | user << 2]
+    }
+    // copy-on-write and uncached mappings get a marker
+    pub fn marker(&self) -> &'static str {
+        if self.flags.contains(super::cow::COW) {
+            "cow"
+        } else if self.flags.contains(PageTableFlags::NO_CACHE) {
+            "nc"
+        } else {
+            ""
+        }
+    }
+}
+// bits that change with every access and would split otherwise equal runs
+const IGNORED: PageTableFlags = PageTableFlags::ACCESSED
+    .union(PageTableFlags::DIRTY)
+    .union(PageTableFlags::HUGE_PAGE);
+// calls `f` for every mapped range of the active page tables in address order;
+// `f` runs with the mapper locked, so it must not touch the heap
+pub fn for_each_mapping(mut f: impl FnMut(&MappedRange)) {
+    super::with_active_table(|mapper| {
+        let mut current: Option<MappedRange> = None;
+        let mut emit = |next: MappedRange| {
+            if let Some(range) = current.as_mut() {
+                if range.extend(&next) {
+                    return;
+                }
+                f(range);
+            }
+            current = Some(next);
+        };
+        let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        walk(mapper.level_4_table(), 4, 0, inherited, &mut emit);
+        if let Some(range) = current {
+            f(&range);
+        }
+    });
+}
+// `inherited` holds WRITABLE and USER_ACCESSIBLE only if every parent entry
+// allows them, and NO_EXECUTE if any parent forbids execution
+fn walk(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    inherited: PageTableFlags,
+    emit: &mut impl FnMut(MappedRange),
+) {
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+    for (index, entry) in table.iter().enumerate() {
+        let raw = entry.flags();
+        if !raw.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let allowed = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let flags = (raw - allowed) | (raw & inherited & allowed) | (inherited & PageTableFlags::NO_EXECUTE);
+        // sign extends addresses in the upper half
+        let start = VirtAddr::new_truncate(base + index as u64 * entry_size);
+        let page_size = match level {
+            1 => PageSizeKind::Size4KiB,
+            2 if raw.contains(PageTableFlags::HUGE_PAGE) => PageSizeKind::Size2MiB,
+            3 if raw.contains(PageTableFlags::HUGE_PAGE) => PageSizeKind::Size1GiB,
+            _ => {
+                let next = super::phys_to_virt(entry.addr());
+                let next = unsafe { &*next.as_ptr::<PageTable>() };
+                walk(next, level - 1, start.as_u64(), flags, emit);
+                continue;
+            }
+        };
+        emit(MappedRange {
+            start,
+            size: entry_size,
+            phys: entry.addr(),
+            flags: flags - IGNORED,
+            page_size,
+        });
+    }
+}
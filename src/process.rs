@@ -0,0 +1,294 @@
+// processes: a user program together with its own address space, a pid, a
+// parent and an exit status that stays around until the process is reaped;
+// they fork, exec and wait for their children like on unix
+use crate::elf::{self, ElfError};
+use crate::interrupts::TICK_COUNTER;
+use crate::memory::{address_space, vma::VmaError, AddressSpace};
+use crate::thread::{self, ThreadId};
+use crate::usermode::{self, UserExit, UserRegisters};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+    pub fn from_raw(raw: u64) -> Self {
+        Pid(raw)
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Ready,
+    Running,
+    // exited or faulted, waiting to be reaped
+    Exited(UserExit),
+}
+impl ProcessState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ProcessState::Ready => "Ready",
+            ProcessState::Running => "Running",
+            ProcessState::Exited(UserExit::Exit(_)) => "Exited",
+            ProcessState::Exited(_) => "Faulted",
+        }
+    }
+}
+// what a process has used so far
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub ticks: u64,
+    pub syscalls: u64,
+    pub pages: u64,
+}
+pub struct Process {
+    pid: Pid,
+    parent: Option<Pid>,
+    // reparented to the kernel, which drops it as soon as it exits
+    orphan: bool,
+    name: String,
+    state: ProcessState,
+    usage: Usage,
+    // where the process starts or continues
+    registers: UserRegisters,
+    // released on exit, a zombie only keeps its status
+    space: Option<Box<AddressSpace>>,
+}
+#[derive(Debug, Clone)]
+pub struct ProcessSnapshot {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub state: ProcessState,
+    pub usage: Usage,
+}
+// what every program finds in envp
+pub const ENVIRONMENT: &[&str] = &["TERM=vga"];
+lazy_static! {
+    static ref PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+}
+// pid of the process the running thread is in, 0 for none
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+fn with_processes<R>(f: impl FnOnce(&mut BTreeMap<Pid, Process>) -> R) -> R {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| f(&mut PROCESSES.lock()))
+}
+// loads `image` into a new address space and registers it as a ready process
+pub fn spawn(
+    name: &str,
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    parent: Option<Pid>,
+) -> Result<Pid, ElfError> {
+    let mut space = Box::new(AddressSpace::new()?);
+    let loaded = elf::load(&mut space, image, argv, envp)?;
+    let pid = Pid::new();
+    let process = Process {
+        pid,
+        parent,
+        orphan: false,
+        name: String::from(name),
+        state: ProcessState::Ready,
+        usage: Usage::default(),
+        registers: UserRegisters::new(loaded.entry, loaded.stack_pointer),
+        space: Some(space),
+    };
+    with_processes(|processes| processes.insert(pid, process));
+    Ok(pid)
+}
+// runs `pid` on a kernel thread of its own, dropping the process if there
+// is no memory for the thread
+pub fn start(pid: Pid) -> Result<ThreadId, VmaError> {
+    let started = thread::spawn("process", move || {
+        run(pid);
+    });
+    if started.is_err() {
+        let process = with_processes(|processes| processes.remove(&pid));
+        drop(process);
+    }
+    started
+}
+// switches to the address space of `pid` and runs it on the calling thread
+// until it exits or faults, None if there is no such process ready to run
+pub fn run(pid: Pid) -> Option<UserExit> {
+    let registers = with_processes(|processes| {
+        let process = processes.get_mut(&pid).filter(|p| p.state == ProcessState::Ready)?;
+        process.state = ProcessState::Running;
+        // the context switch: from here on cr3 points at the process tables
+        process.space.as_ref()?.activate();
+        Some(process.registers)
+    })?;
+    let previous = swap_current(pid.as_u64());
+    let started = TICK_COUNTER.load(Ordering::Relaxed) as u64;
+    let exit = unsafe { usermode::enter_registers(&registers) };
+    address_space::activate_kernel();
+    swap_current(previous);
+    let ticks = TICK_COUNTER.load(Ordering::Relaxed) as u64 - started;
+    finish(pid, exit, ticks);
+    Some(exit)
+}
+// turns an exited process into a zombie: its memory goes right away, its
+// zombie children too since nobody can wait for them anymore, and the
+// running ones are reparented to the kernel
+fn finish(pid: Pid, exit: UserExit, ticks: u64) {
+    let released = with_processes(|processes| {
+        let process = processes.get_mut(&pid)?;
+        process.state = ProcessState::Exited(exit);
+        process.usage.ticks += ticks;
+        let space = process.space.take()?;
+        process.usage.pages = space.mapped_pages();
+        let orphan = process.orphan;
+        let children: Vec<Pid> = processes.values().filter(|p| p.parent == Some(pid)).map(|p| p.pid).collect();
+        let mut released = Vec::new();
+        for child in children {
+            let process = processes.get_mut(&child).unwrap();
+            match process.state {
+                ProcessState::Exited(_) => released.extend(processes.remove(&child)),
+                _ => {
+                    process.parent = None;
+                    process.orphan = true;
+                }
+            }
+        }
+        if orphan {
+            released.extend(processes.remove(&pid));
+        }
+        Some((space, released))
+    });
+    // address spaces are freed outside the lock
+    drop(released);
+}
+// a copy of `parent` sharing its memory copy-on-write, started on its own
+// thread with `registers`
+pub fn fork(parent: Pid, registers: &UserRegisters) -> Result<Pid, VmaError> {
+    let child = with_processes(|processes| {
+        let process = processes.get(&parent).ok_or(VmaError::NotFound)?;
+        let space = Box::new(process.space.as_ref().ok_or(VmaError::NotFound)?.fork()?);
+        let pid = Pid::new();
+        let child = Process {
+            pid,
+            parent: Some(parent),
+            orphan: false,
+            name: process.name.clone(),
+            state: ProcessState::Ready,
+            usage: Usage::default(),
+            registers: *registers,
+            space: Some(space),
+        };
+        processes.insert(pid, child);
+        Ok::<_, VmaError>(pid)
+    })?;
+    start(child)?;
+    Ok(child)
+}
+// replaces the program of the running process `pid` by `image`, returning
+// the registers it starts with
+pub fn exec(pid: Pid, name: &str, image: &[u8], argv: &[&str]) -> Result<UserRegisters, ElfError> {
+    let mut space = Box::new(AddressSpace::new()?);
+    let loaded = elf::load(&mut space, image, argv, ENVIRONMENT)?;
+    let old = with_processes(|processes| {
+        let process = processes.get_mut(&pid).expect("exec of a process that isn't running");
+        // the old address space can only go once it isn't active anymore
+        space.activate();
+        process.name = String::from(name);
+        process.space.replace(space)
+    });
+    drop(old);
+    Ok(UserRegisters::new(loaded.entry, loaded.stack_pointer))
+}
+// waits for a child of `parent` to exit, `pid` or any with None, and reaps
+// it; None if there is no such child
+pub fn wait(parent: Pid, pid: Option<Pid>) -> Option<(Pid, UserExit)> {
+    let child = |process: &&Process| process.parent == Some(parent) && pid.is_none_or(|pid| pid == process.pid);
+    loop {
+        let found = with_processes(|processes| {
+            let exited = processes.values().filter(child).find_map(|process| match process.state {
+                ProcessState::Exited(exit) => Some((process.pid, exit)),
+                _ => None,
+            });
+            match exited {
+                Some((pid, exit)) => {
+                    processes.remove(&pid);
+                    Some(Some((pid, exit)))
+                }
+                None if processes.values().any(|process| child(&process)) => None,
+                None => Some(None),
+            }
+        });
+        match found {
+            Some(found) => return found,
+            None => thread::sleep(1),
+        }
+    }
+}
+// drops an exited process, returning its exit status
+pub fn reap(pid: Pid) -> Option<UserExit> {
+    let process = with_processes(|processes| match processes.get(&pid)?.state {
+        ProcessState::Exited(_) => processes.remove(&pid),
+        _ => None,
+    })?;
+    match process.state {
+        ProcessState::Exited(exit) => Some(exit),
+        _ => unreachable!(),
+    }
+}
+// whether `pid` is `ancestor` or was started by it, through any number of
+// generations
+pub fn descends_from(pid: Pid, ancestor: Pid) -> bool {
+    with_processes(|processes| {
+        let mut next = Some(pid);
+        while let Some(pid) = next {
+            if pid == ancestor {
+                return true;
+            }
+            next = processes.get(&pid).and_then(|process| process.parent);
+        }
+        false
+    })
+}
+// the process running in ring 3, if any
+pub fn current() -> Option<Pid> {
+    match CURRENT.load(Ordering::Relaxed) {
+        0 => None,
+        raw => Some(Pid(raw)),
+    }
+}
+// exchanges the current pid, for the scheduler
+pub(crate) fn swap_current(raw: u64) -> u64 {
+    CURRENT.swap(raw, Ordering::Relaxed)
+}
+// counts a system call against the current process, called from the
+// system call entry so it only tries the lock
+pub fn account_syscall() {
+    let Some(pid) = current() else { return };
+    if let Some(mut processes) = PROCESSES.try_lock() {
+        if let Some(process) = processes.get_mut(&pid) {
+            process.usage.syscalls += 1;
+        }
+    }
+}
+pub fn snapshot_processes() -> Vec<ProcessSnapshot> {
+    with_processes(|processes| {
+        processes
+            .values()
+            .map(|process| ProcessSnapshot {
+                pid: process.pid,
+                parent: process.parent,
+                name: process.name.clone(),
+                state: process.state,
+                usage: Usage {
+                    pages: process.space.as_ref().map_or(process.usage.pages, |space| space.mapped_pages()),
+                    ..process.usage
+                },
+            })
+            .collect()
+    })
+}
@@ -0,0 +1,10 @@
+// user programs built into the kernel image, their sources live in user/
+pub static PROGRAMS: [(&str, &[u8]); 3] = [
+    ("hello", include_bytes!("../user/bin/hello")),
+    ("keys", include_bytes!("../user/bin/keys")),
+    ("family", include_bytes!("../user/bin/family")),
+];
+// the ELF image of the built in program called `name`
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS.iter().find(|(program, _)| *program == name).map(|(_, image)| *image)
+}
@@ -0,0 +1,394 @@
+// system calls from ring 3, through `syscall` or the `int 0x80` fallback:
+// the number goes in rax, up to three arguments in rdi, rsi and rdx, and
+// the result comes back in rax, negative values being errors
+use crate::interrupts::TICK_COUNTER;
+use crate::elf::ElfError;
+use crate::memory::{self, cow, vma};
+use crate::process::{self, Pid};
+use crate::usermode::{self, UserExit, UserRegisters};
+use crate::{gdt, programs, task::keyboard, thread, vga_buffer::WRITER};
+use alloc::{string::String, vec::Vec};
+use core::arch::naked_asm;
+use core::sync::atomic::Ordering;
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::{mapper::TranslateResult, PageTableFlags, Translate},
+    VirtAddr,
+};
+pub const INTERRUPT_VECTOR: u8 = 0x80;
+// the numbers are part of the user interface and never change meaning
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Syscall {
+    // write(buffer, len) -> bytes written
+    Write = 0,
+    // read_key() -> unicode scalar of the next key, blocking
+    ReadKey = 1,
+    // sleep(ticks) -> 0
+    Sleep = 2,
+    // exit(value), never returns
+    Exit = 3,
+    // ticks() -> timer ticks since boot
+    Ticks = 4,
+    // fork() -> pid of the child in the parent, 0 in the child
+    Fork = 5,
+    // exec(name, name_len, argv) replaces the program with the built in one
+    // called `name`, argv being a null terminated array of C strings;
+    // doesn't return on success
+    Exec = 6,
+    // wait(pid, status) -> pid of an exited child, `pid` -1 waits for any
+    // and the exit status is stored at `status` unless that is null
+    Wait = 7,
+    // getpid() -> pid of the calling process
+    GetPid = 8,
+}
+impl Syscall {
+    pub fn from_u64(number: u64) -> Option<Self> {
+        match number {
+            0 => Some(Syscall::Write),
+            1 => Some(Syscall::ReadKey),
+            2 => Some(Syscall::Sleep),
+            3 => Some(Syscall::Exit),
+            4 => Some(Syscall::Ticks),
+            5 => Some(Syscall::Fork),
+            6 => Some(Syscall::Exec),
+            7 => Some(Syscall::Wait),
+            8 => Some(Syscall::GetPid),
+            _ => None,
+        }
+    }
+}
+// returned negated in rax
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    UnknownCall = 1,
+    BadAddress = 2,
+    // wait found no child to wait for
+    NoChild = 3,
+    // exec was given an unknown or broken program
+    BadProgram = 4,
+    OutOfMemory = 5,
+    // process calls made by code that isn't running as a process
+    NoProcess = 6,
+}
+impl SyscallError {
+    // the value user code sees in rax
+    pub fn as_u64(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+}
+// handlers take their arguments from the saved registers, which they may
+// also change to decide where the program continues
+type Handler = fn(&mut UserRegisters) -> Result<u64, SyscallError>;
+// indexed by `Syscall`
+static HANDLERS: [Handler; 9] = [
+    sys_write, sys_read_key, sys_sleep, sys_exit, sys_ticks, sys_fork, sys_exec, sys_wait, sys_getpid,
+];
+// limits for what exec copies out of the old program
+const MAX_ARGUMENTS: usize = 16;
+const MAX_STRING: u64 = 256;
+// stack pointers for the `syscall` entry, which unlike interrupts doesn't
+// switch stacks by itself
+static mut KERNEL_STACK: u64 = 0;
+static mut USER_STACK: u64 = 0;
+// enables `syscall`/`sysret`, needs the gdt loaded
+pub fn init() {
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    let (user_code, user_data) = gdt::user_selectors();
+    Star::write(user_code, user_data, kernel_code, kernel_data).expect("bad gdt layout for sysret");
+    LStar::write(VirtAddr::from_ptr(syscall_entry as *const ()));
+    // the kernel side starts with interrupts off, like an interrupt gate
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe {
+        KERNEL_STACK = gdt::privilege_stack_top().as_u64();
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+// the stack `syscall` switches to, kept equal to the privilege stack
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { KERNEL_STACK = top.as_u64() };
+}
+// address of the handler for `INTERRUPT_VECTOR`
+pub fn interrupt_handler_addr() -> VirtAddr {
+    VirtAddr::from_ptr(interrupt_entry as *const ())
+}
+// called by both entries with the frame they pushed, the result goes to rax
+extern "C" fn dispatch(registers: &mut UserRegisters) {
+    process::account_syscall();
+    let result = match Syscall::from_u64(registers.rax) {
+        Some(call) => HANDLERS[call as usize](registers),
+        None => Err(SyscallError::UnknownCall),
+    };
+    registers.rax = result.unwrap_or_else(SyscallError::as_u64);
+}
+// checks that every page of a user range is mapped for ring 3, and for
+// writes also writable, possibly after a copy-on-write fault
+fn check_user(addr: u64, len: u64, write: bool) -> Result<(), SyscallError> {
+    let end = addr.checked_add(len).ok_or(SyscallError::BadAddress)?;
+    if addr < vma::USER_WINDOW_START || end > vma::USER_WINDOW_END {
+        return Err(SyscallError::BadAddress);
+    }
+    let allowed = memory::with_active_table(|mapper| {
+        (addr & !0xfff..end).step_by(4096).all(|page| match mapper.translate(VirtAddr::new(page)) {
+            TranslateResult::Mapped { flags, .. } => {
+                flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                    && (!write || flags.intersects(PageTableFlags::WRITABLE | cow::COW))
+            }
+            _ => false,
+        })
+    });
+    match allowed {
+        true => Ok(()),
+        false => Err(SyscallError::BadAddress),
+    }
+}
+// user memory the kernel may read
+fn user_bytes(addr: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    check_user(addr, len, false)?;
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+fn read_user_u64(addr: u64) -> Result<u64, SyscallError> {
+    let bytes = user_bytes(addr, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+fn write_user_u64(addr: u64, value: u64) -> Result<(), SyscallError> {
+    check_user(addr, 8, true)?;
+    unsafe { (addr as *mut u64).write_unaligned(value) };
+    Ok(())
+}
+// copies a string out of user memory, `len` bytes or up to the nul
+fn user_string(addr: u64, len: Option<u64>) -> Result<String, SyscallError> {
+    let bytes = match len {
+        Some(len) if len <= MAX_STRING => user_bytes(addr, len)?,
+        Some(_) => return Err(SyscallError::BadAddress),
+        None => {
+            let mut len = 0;
+            while user_bytes(addr + len, 1)?[0] != 0 {
+                len += 1;
+                if len > MAX_STRING {
+                    return Err(SyscallError::BadAddress);
+                }
+            }
+            user_bytes(addr, len)?
+        }
+    };
+    core::str::from_utf8(bytes).map(String::from).map_err(|_| SyscallError::BadAddress)
+}
+fn current_process() -> Result<Pid, SyscallError> {
+    process::current().ok_or(SyscallError::NoProcess)
+}
+fn sys_write(registers: &mut UserRegisters) -> Result<u64, SyscallError> {
+    let bytes = user_bytes(registers.rdi, registers.rsi)?;
+    interrupts::without_interrupts(|| WRITER.lock().write_bytes(bytes));
+    Ok(registers.rsi)
+}
+// waits until the caller's group has the keyboard and a key comes in; code
+// not running as a process reads it while the kernel tasks have it
+fn sys_read_key(_: &mut UserRegisters) -> Result<u64, SyscallError> {
+    lazy_static! {
+        // decoder state for the foreground it was last used by, so modifiers
+        // held for an earlier owner don't carry over
+        static ref KEYBOARD: Mutex<(Option<Pid>, Keyboard<layouts::Us104Key, ScancodeSet1>)> =
+            Mutex::new((None, decoder()));
+    }
+    fn decoder() -> Keyboard<layouts::Us104Key, ScancodeSet1> {
+        Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore)
+    }
+    let caller = process::current();
+    let owns_keyboard = || match (keyboard::foreground(), caller) {
+        (None, None) => true,
+        (Some(foreground), Some(caller)) => process::descends_from(caller, foreground),
+        _ => false,
+    };
+    loop {
+        while owns_keyboard() {
+            let Some(scancode) = keyboard::pop_scancode() else { break };
+            let mut state = KEYBOARD.lock();
+            let foreground = keyboard::foreground();
+            if state.0 != foreground {
+                *state = (foreground, decoder());
+            }
+            let keyboard = &mut state.1;
+            if let Ok(Some(event)) = keyboard.add_byte(scancode) {
+                if let Some(DecodedKey::Unicode(character)) = keyboard.process_keyevent(event) {
+                    return Ok(character as u64);
+                }
+            }
+        }
+        // the keyboard interrupt ends the wait
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+    }
+}
+// blocks the calling thread, other threads run in the meantime
+fn sys_sleep(registers: &mut UserRegisters) -> Result<u64, SyscallError> {
+    thread::sleep(registers.rdi);
+    Ok(0)
+}
+fn sys_exit(registers: &mut UserRegisters) -> Result<u64, SyscallError> {
+    usermode::leave(UserExit::Exit(registers.rdi))
+}
+fn sys_ticks(_: &mut UserRegisters) -> Result<u64, SyscallError> {
+    Ok(TICK_COUNTER.load(Ordering::Relaxed) as u64)
+}
+fn sys_fork(registers: &mut UserRegisters) -> Result<u64, SyscallError> {
+    let parent = current_process()?;
+    let mut child = *registers;
+    child.rax = 0;
+    match process::fork(parent, &child) {
+        Ok(pid) => Ok(pid.as_u64()),
+        Err(_) => Err(SyscallError::OutOfMemory),
+    }
+}
+fn sys_exec(registers: &mut UserRegisters) -> Result<u64, SyscallError> {
+    let pid = current_process()?;
+    let name = user_string(registers.rdi, Some(registers.rsi))?;
+    // the strings live in the program that is about to be replaced
+    let mut argv = Vec::new();
+    if registers.rdx != 0 {
+        loop {
+            let pointer = read_user_u64(registers.rdx + argv.len() as u64 * 8)?;
+            if pointer == 0 {
+                break;
+            }
+            if argv.len() == MAX_ARGUMENTS {
+                return Err(SyscallError::BadAddress);
+            }
+            argv.push(user_string(pointer, None)?);
+        }
+    }
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let image = programs::find(&name).ok_or(SyscallError::BadProgram)?;
+    let started = match process::exec(pid, &name, image, &argv) {
+        Ok(started) => started,
+        Err(ElfError::Map(_)) => return Err(SyscallError::OutOfMemory),
+        Err(_) => return Err(SyscallError::BadProgram),
+    };
+    // returning from the system call enters the new program
+    *registers = started;
+    Ok(0)
+}
+fn sys_wait(registers: &mut UserRegisters) -> Result<u64, SyscallError> {
+    let parent = current_process()?;
+    let target = match registers.rdi {
+        u64::MAX => None,
+        raw => Some(Pid::from_raw(raw)),
+    };
+    let status = registers.rsi;
+    // fail before reaping the child rather than after
+    if status != 0 {
+        check_user(status, 8, true)?;
+    }
+    let (child, exit) = process::wait(parent, target).ok_or(SyscallError::NoChild)?;
+    if status != 0 {
+        write_user_u64(status, exit.status())?;
+    }
+    Ok(child.as_u64())
+}
+fn sys_getpid(_: &mut UserRegisters) -> Result<u64, SyscallError> {
+    Ok(current_process()?.as_u64())
+}
+// `syscall` leaves the user rip in rcx and rflags in r11 and keeps the user
+// stack, so switch stacks first and build the same frame as `int 0x80`, with
+// the segments left zero; everything but rax, rcx and r11 survives
+#[unsafe(naked)]
+unsafe extern "C" fn syscall_entry() {
+    naked_asm!(
+        "mov [rip + {user}], rsp",
+        "mov rsp, [rip + {kernel}]",
+        "push 0",
+        "push qword ptr [rip + {user}]",
+        "push r11",
+        "push 0",
+        "push rcx",
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "call {dispatch}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "pop rcx",
+        "add rsp, 8",
+        "pop r11",
+        "pop rsp",
+        "sysretq",
+        user = sym USER_STACK,
+        kernel = sym KERNEL_STACK,
+        dispatch = sym dispatch,
+    )
+}
+// `int 0x80` already runs on the privilege stack and pushed the interrupt
+// frame, everything but rax survives
+#[unsafe(naked)]
+unsafe extern "C" fn interrupt_entry() {
+    naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "call {dispatch}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+        dispatch = sym dispatch,
+    )
+}
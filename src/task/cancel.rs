@@ -0,0 +1,70 @@
+// cancellation tokens: a kill request cancels the token of a task and gives
+// it a grace period to notice and wind down before it is dropped
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}};
+use spin::Mutex;
+struct CancelState {
+    cancelled: bool,
+    wakers: Vec<Waker>,
+    // run once a cancelled task is gone, whether it returned or was dropped
+    hooks: Vec<Box<dyn FnOnce() + Send>>,
+}
+#[derive(Clone)]
+pub struct CancellationToken(Arc<Mutex<CancelState>>);
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken(Arc::new(Mutex::new(CancelState {
+            cancelled: false,
+            wakers: Vec::new(),
+            hooks: Vec::new(),
+        })))
+    }
+    // false if it was cancelled already
+    pub fn cancel(&self) -> bool {
+        let wakers = {
+            let mut state = self.0.lock();
+            if state.cancelled {
+                return false;
+            }
+            state.cancelled = true;
+            core::mem::take(&mut state.wakers)
+        };
+        wakers.into_iter().for_each(Waker::wake);
+        true
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.lock().cancelled
+    }
+    // resolves once the token is cancelled, to select on next to other work
+    pub fn cancelled(&self) -> WaitForCancel {
+        WaitForCancel(self.clone())
+    }
+    pub fn on_cancel(&self, hook: impl FnOnce() + Send + 'static) {
+        self.0.lock().hooks.push(Box::new(hook));
+    }
+    pub(crate) fn run_hooks(&self) {
+        let hooks = core::mem::take(&mut self.0.lock().hooks);
+        for hook in hooks {
+            hook();
+        }
+    }
+}
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+pub struct WaitForCancel(CancellationToken);
+impl Future for WaitForCancel {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let mut state = (self.0).0.lock();
+        if state.cancelled {
+            return Poll::Ready(());
+        }
+        if !state.wakers.iter().any(|waker| waker.will_wake(context.waker())) {
+            state.wakers.push(context.waker().clone());
+        }
+        Poll::Pending
+    }
+}
@@ -1,14 +1,17 @@
-use super::{Task, TaskId};
+use super::{cancel::CancellationToken, join::JoinHandle, Priority, Task, TaskId};
+use crate::allocator::slab::{SlabBox, SlabCache};
 use alloc::{
-    collections::{BTreeMap, BTreeSet},
-    sync::Arc,
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
     task::Wake,
     vec::Vec,
 };
+use core::future::Future;
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
 use lazy_static::lazy_static;
 use spin::Mutex;
+use x86_64::instructions::interrupts;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TaskState {
@@ -33,10 +36,17 @@ impl TaskState {
 pub struct TaskSnapshot {
     pub id: u64,
     pub name: &'static str,
+    pub priority: Priority,
     pub state: TaskState,
     pub poll_count: u64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    // no frames left for the task's slab
+    OutOfMemory,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KillRequestResult {
     Queued,
@@ -44,16 +54,77 @@ pub enum KillRequestResult {
     NotFound,
 }
 
+// how a kill request ends a task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KillMode {
+    // cancel its token and drop it once the grace period is over
+    Cancel,
+    // drop it right away without running cleanup hooks
+    Force,
+}
+
 #[derive(Debug, Clone, Copy)]
 struct TaskStats {
     name: &'static str,
+    priority: Priority,
     state: TaskState,
     poll_count: u64,
 }
 
 lazy_static! {
     static ref TASK_STATS: Mutex<BTreeMap<u64, TaskStats>> = Mutex::new(BTreeMap::new());
-    static ref KILL_REQUESTS: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+    static ref KILL_REQUESTS: Mutex<BTreeMap<u64, KillMode>> = Mutex::new(BTreeMap::new());
+    // token of the task being polled, for `current_token`
+    static ref RUNNING_TOKEN: Mutex<Option<CancellationToken>> = Mutex::new(None);
+    // spawner of the executor that is running, used by `task::spawn`
+    static ref RUNNING_SPAWNER: Mutex<Option<Spawner>> = Mutex::new(None);
+}
+// cloneable handle that queues tasks on an executor, safe to use from the
+// tasks it is polling and from other threads. it doesn't keep the executor's
+// queue alive, spawning fails once the executor is gone
+#[derive(Clone)]
+pub struct Spawner {
+    spawned: Weak<Mutex<VecDeque<Task>>>,
+}
+impl Spawner {
+    // None if the executor has been dropped
+    pub fn spawn<T: Send + 'static>(&self, future: impl Future<Output = T> + Send + 'static) -> Option<JoinHandle<T>> {
+        self.spawn_named("task", Priority::Normal, future)
+    }
+    pub fn spawn_named<T: Send + 'static>(
+        &self,
+        name: &'static str,
+        priority: Priority,
+        future: impl Future<Output = T> + Send + 'static,
+    ) -> Option<JoinHandle<T>> {
+        let spawned = self.spawned.upgrade()?;
+        let (task, handle) = Task::with_handle(name, priority, future);
+        register_task(task.id(), name, priority);
+        interrupts::without_interrupts(|| spawned.lock().push_back(task));
+        Some(handle)
+    }
+}
+fn running_spawner() -> Option<Spawner> {
+    interrupts::without_interrupts(|| RUNNING_SPAWNER.lock().clone())
+}
+// queues `future` on the executor that is running, None if there is none
+pub fn spawn<T: Send + 'static>(future: impl Future<Output = T> + Send + 'static) -> Option<JoinHandle<T>> {
+    running_spawner()?.spawn(future)
+}
+pub fn spawn_named<T: Send + 'static>(
+    name: &'static str,
+    priority: Priority,
+    future: impl Future<Output = T> + Send + 'static,
+) -> Option<JoinHandle<T>> {
+    running_spawner()?.spawn_named(name, priority, future)
+}
+
+// cancellation token of the task calling this, None outside of tasks
+pub fn current_token() -> Option<CancellationToken> {
+    interrupts::without_interrupts(|| RUNNING_TOKEN.lock().clone())
+}
+fn set_running_token(token: Option<CancellationToken>) {
+    interrupts::without_interrupts(|| *RUNNING_TOKEN.lock() = token);
 }
 
 pub fn snapshot_tasks() -> Vec<TaskSnapshot> {
@@ -63,21 +134,55 @@ pub fn snapshot_tasks() -> Vec<TaskSnapshot> {
         .map(|(&id, info)| TaskSnapshot {
             id,
             name: info.name,
+            priority: info.priority,
             state: info.state,
             poll_count: info.poll_count,
         })
         .collect()
 }
+// changes the class a task is queued in from its next wakeup on, false if
+// there is no such task
+pub fn set_priority(task_id: u64, priority: Priority) -> bool {
+    match TASK_STATS.lock().get_mut(&task_id) {
+        Some(info) => {
+            info.priority = priority;
+            true
+        }
+        None => false,
+    }
+}
+fn task_priority(task_id: TaskId) -> Priority {
+    TASK_STATS
+        .lock()
+        .get(&task_id.as_u64())
+        .map_or(Priority::Normal, |info| info.priority)
+}
 
+// cancels the task's token, it is dropped if it hasn't returned by the
+// end of the grace period
 pub fn request_kill(task_id: u64) -> KillRequestResult {
-    if !TASK_STATS.lock().contains_key(&task_id) {
-        return KillRequestResult::NotFound;
-    }
+    queue_kill(task_id, KillMode::Cancel)
+}
+// drops the task without warning, like before cancellation existed
+pub fn force_kill(task_id: u64) -> KillRequestResult {
+    queue_kill(task_id, KillMode::Force)
+}
+fn queue_kill(task_id: u64, mode: KillMode) -> KillRequestResult {
+    let cancelling = match TASK_STATS.lock().get(&task_id) {
+        Some(info) => info.state == TaskState::KillRequested,
+        None => return KillRequestResult::NotFound,
+    };
 
     {
         let mut requests = KILL_REQUESTS.lock();
-        if !requests.insert(task_id) {
-            return KillRequestResult::AlreadyQueued;
+        match requests.get(&task_id) {
+            Some(&queued) if queued == mode || queued == KillMode::Force => {
+                return KillRequestResult::AlreadyQueued;
+            }
+            None if cancelling && mode == KillMode::Cancel => return KillRequestResult::AlreadyQueued,
+            _ => {
+                requests.insert(task_id, mode);
+            }
         }
     }
 
@@ -85,11 +190,12 @@ pub fn request_kill(task_id: u64) -> KillRequestResult {
     KillRequestResult::Queued
 }
 
-fn register_task(task_id: TaskId, name: &'static str) {
+fn register_task(task_id: TaskId, name: &'static str, priority: Priority) {
     TASK_STATS.lock().insert(
         task_id.as_u64(),
         TaskStats {
             name,
+            priority,
             state: TaskState::Ready,
             poll_count: 0,
         },
@@ -115,38 +221,97 @@ fn bump_poll_count(task_id: TaskId) {
     }
 }
 
+static TASK_CACHE: SlabCache<Task> = SlabCache::new("task");
+// picks a lower class task before higher ones once it has been passed over
+// this many times
+const AGING_LIMIT: u64 = 8;
+// timer ticks a cancelled task gets to return before it is dropped
+pub const GRACE_TICKS: u64 = 36;
+fn ticks() -> u64 {
+    crate::interrupts::TICK_COUNTER.load(core::sync::atomic::Ordering::Relaxed) as u64
+}
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
+    tasks: BTreeMap<TaskId, SlabBox<Task>>,
+    // filled by wakers, which may run in interrupt handlers
     task_queue: Arc<ArrayQueue<TaskId>>,
+    // woken tasks by class, with the pick count they were queued at
+    ready: [VecDeque<(TaskId, u64)>; Priority::COUNT],
+    picks: u64,
     waker_cache: BTreeMap<TaskId, Waker>,
+    // tasks from spawners, moved into `tasks` between polls
+    spawned: Arc<Mutex<VecDeque<Task>>>,
+    // cancelled tasks by the tick they get dropped at
+    cancelling: BTreeMap<TaskId, u64>,
 }
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
             task_queue: Arc::new(ArrayQueue::new(100)),
+            ready: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            picks: 0,
             waker_cache: BTreeMap::new(),
+            spawned: Arc::new(Mutex::new(VecDeque::new())),
+            cancelling: BTreeMap::new(),
         }
     }
-    pub fn spawn(&mut self, task: Task) {
+    pub fn spawn(&mut self, task: Task) -> Result<(), SpawnError> {
         let task_id = task.id();
-        let task_name = task.name();
+        register_task(task_id, task.name(), task.priority());
+        self.insert_task(task).inspect_err(|_| unregister_task(task_id))
+    }
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawned: Arc::downgrade(&self.spawned),
+        }
+    }
+    fn insert_task(&mut self, task: Task) -> Result<(), SpawnError> {
+        let task_id = task.id();
+        let task = TASK_CACHE.alloc(task).ok_or(SpawnError::OutOfMemory)?;
         if self.tasks.insert(task_id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        register_task(task_id, task_name);
         self.task_queue.push(task_id).expect("queue full");
         set_task_state(task_id, TaskState::Ready);
+        Ok(())
+    }
+    // takes over `task::spawn` until another executor runs, returns the
+    // spawner it replaced
+    fn make_running(&self) -> Option<Spawner> {
+        interrupts::without_interrupts(|| RUNNING_SPAWNER.lock().replace(self.spawner()))
+    }
+    fn take_spawned(&mut self) {
+        while let Some(task) = interrupts::without_interrupts(|| self.spawned.lock().pop_front()) {
+            let task_id = task.id();
+            // killed before it got here, it never runs
+            if KILL_REQUESTS.lock().remove(&task_id.as_u64()).is_some() {
+                unregister_task(task_id);
+                continue;
+            }
+            // dropping the task tells its join handle it was cancelled
+            if self.insert_task(task).is_err() {
+                unregister_task(task_id);
+            }
+        }
     }
     pub fn run(&mut self) -> ! {
+        self.make_running();
         loop {
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
     }
+    // polls tasks until none is ready, for code that can't hand the cpu to
+    // `run` for good
+    pub fn run_until_idle(&mut self) {
+        let previous = self.make_running();
+        self.run_ready_tasks();
+        // hands `task::spawn` back to the executor that was running before
+        interrupts::without_interrupts(|| *RUNNING_SPAWNER.lock() = previous);
+    }
     fn run_ready_tasks(&mut self) {
         self.apply_kill_requests();
-        while let Some(task_id) = self.task_queue.pop() {
+        while let Some(task_id) = self.next_task() {
             let waker = self
                 .waker_cache
                 .entry(task_id)
@@ -157,16 +322,17 @@ impl Executor {
             bump_poll_count(task_id);
 
             let poll_result = match self.tasks.get_mut(&task_id) {
-                Some(task) => task.poll(&mut context),
+                Some(task) => {
+                    set_running_token(Some(task.token().clone()));
+                    let poll_result = task.poll(&mut context);
+                    set_running_token(None);
+                    poll_result
+                }
                 None => continue,
             };
 
             match poll_result {
-                Poll::Ready(()) => {
-                    self.tasks.remove(&task_id);
-                    self.waker_cache.remove(&task_id);
-                    unregister_task(task_id);
-                }
+                Poll::Ready(()) => self.remove_task(task_id, true),
                 Poll::Pending => {
                     set_task_state(task_id, TaskState::Waiting);
                 }
@@ -176,32 +342,110 @@ impl Executor {
         }
         self.apply_kill_requests();
     }
+    // sorts woken tasks into their class and picks the front of the highest
+    // one, unless a task further down has waited too long already
+    fn next_task(&mut self) -> Option<TaskId> {
+        self.take_spawned();
+        while let Some(task_id) = self.task_queue.pop() {
+            let priority = task_priority(task_id);
+            self.ready[priority as usize].push_back((task_id, self.picks));
+        }
+        let aged = self
+            .ready
+            .iter()
+            .enumerate()
+            .filter_map(|(class, queue)| queue.front().map(|&(_, queued_at)| (class, queued_at)))
+            .find(|&(_, queued_at)| self.picks - queued_at >= AGING_LIMIT)
+            .map(|(class, _)| class);
+        let class = aged.or_else(|| self.ready.iter().rposition(|queue| !queue.is_empty()))?;
+        self.picks += 1;
+        self.ready[class].pop_front().map(|(task_id, _)| task_id)
+    }
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
         interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
+        let idle = self.task_queue.is_empty()
+            && self.ready.iter().all(VecDeque::is_empty)
+            && self.spawned.lock().is_empty();
+        if idle {
+            interrupts::enable_and_hlt();
         } else {
             interrupts::enable();
         }
     }
 
     fn apply_kill_requests(&mut self) {
-        let pending_ids: Vec<u64> = {
+        let requests = {
             let mut requests = KILL_REQUESTS.lock();
-            if requests.is_empty() {
-                return;
-            }
-            let ids: Vec<u64> = requests.iter().copied().collect();
-            requests.clear();
-            ids
+            core::mem::take(&mut *requests)
         };
 
-        for raw_id in pending_ids {
+        let mut foreign = Vec::new();
+        for (raw_id, mode) in requests {
             let task_id = TaskId::from_raw(raw_id);
-            self.tasks.remove(&task_id);
-            self.waker_cache.remove(&task_id);
-            unregister_task(task_id);
+            match (mode, self.tasks.get(&task_id)) {
+                (KillMode::Cancel, Some(task)) => {
+                    task.token().cancel();
+                    self.cancelling.entry(task_id).or_insert(ticks() + GRACE_TICKS);
+                    // so that it gets to see the cancellation
+                    self.task_queue.push(task_id).expect("queue full");
+                }
+                (KillMode::Force, Some(_)) => self.remove_task(task_id, false),
+                (_, None) => foreign.push((raw_id, mode)),
+            }
+        }
+        // tasks of other executors stay queued for them, unless they are gone
+        foreign.retain(|&(raw_id, _)| TASK_STATS.lock().contains_key(&raw_id));
+        if !foreign.is_empty() {
+            let mut requests = KILL_REQUESTS.lock();
+            for (raw_id, mode) in foreign {
+                let queued = requests.entry(raw_id).or_insert(mode);
+                if mode == KillMode::Force {
+                    *queued = mode;
+                }
+            }
+        }
+
+        if self.cancelling.is_empty() {
+            return;
+        }
+        let now = ticks();
+        let expired: Vec<TaskId> = self
+            .cancelling
+            .iter()
+            .filter(|&(_, &deadline)| deadline <= now)
+            .map(|(&task_id, _)| task_id)
+            .collect();
+        for task_id in expired {
+            self.remove_task(task_id, true);
+        }
+    }
+    // drops a task, then runs its cleanup hooks if it was cancelled and
+    // `cleanup` is set
+    fn remove_task(&mut self, task_id: TaskId, cleanup: bool) {
+        self.cancelling.remove(&task_id);
+        self.waker_cache.remove(&task_id);
+        unregister_task(task_id);
+        let Some(task) = self.tasks.remove(&task_id) else { return };
+        let token = task.token().clone();
+        drop(task);
+        if cleanup && token.is_cancelled() {
+            token.run_hooks();
+        }
+    }
+}
+// a dropped executor can't take spawned tasks anymore, and the ones it
+// still had leave the task list with it
+impl Drop for Executor {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut running = RUNNING_SPAWNER.lock();
+            if running.as_ref().is_some_and(|spawner| spawner.spawned.as_ptr() == Arc::as_ptr(&self.spawned)) {
+                *running = None;
+            }
+        });
+        let queued = interrupts::without_interrupts(|| core::mem::take(&mut *self.spawned.lock()));
+        for task in queued.iter().map(Task::id).chain(self.tasks.keys().copied()) {
+            unregister_task(task);
         }
     }
 }
@@ -0,0 +1,90 @@
+// handles for awaiting the output of spawned tasks
+use super::{Priority, Task};
+use alloc::sync::Arc;
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}};
+use spin::Mutex;
+// what a join handle gets when its task was dropped before finishing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+struct JoinState<T> {
+    result: Option<Result<T, Cancelled>>,
+    finished: bool,
+    waker: Option<Waker>,
+}
+impl<T> JoinState<T> {
+    fn finish(&mut self, result: Result<T, Cancelled>) {
+        self.result = Some(result);
+        self.finished = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+// resolves to the output of a task, dropping it detaches the task
+pub struct JoinHandle<T> {
+    id: u64,
+    state: Arc<Mutex<JoinState<T>>>,
+}
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    // true once the task returned or was dropped
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+}
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Cancelled>;
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None if state.finished => panic!("JoinHandle polled after it resolved"),
+            None => {
+                state.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+// lives inside the task and reports it cancelled if the future is dropped
+// before it gets to hand over the output
+struct Completion<T>(Arc<Mutex<JoinState<T>>>);
+impl<T> Completion<T> {
+    fn complete(self, output: T) {
+        self.0.lock().finish(Ok(output));
+    }
+}
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        if !state.finished {
+            state.finish(Err(Cancelled));
+        }
+    }
+}
+impl Task {
+    // wraps a future with any output, which goes to the returned handle
+    pub fn with_handle<T: Send + 'static>(
+        name: &'static str,
+        priority: Priority,
+        future: impl Future<Output = T> + Send + 'static,
+    ) -> (Task, JoinHandle<T>) {
+        let state = Arc::new(Mutex::new(JoinState {
+            result: None,
+            finished: false,
+            waker: None,
+        }));
+        let completion = Completion(state.clone());
+        let task = Task::new_named(name, priority, async move {
+            let output = future.await;
+            completion.complete(output);
+        });
+        let handle = JoinHandle {
+            id: task.id().as_u64(),
+            state,
+        };
+        (task, handle)
+    }
+}
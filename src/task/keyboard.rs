@@ -6,8 +6,25 @@ use futures_util::stream::{Stream, StreamExt};
 use futures_util::task::AtomicWaker;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use crate::print;
+use crate::process::Pid;
+use core::sync::atomic::{AtomicU64, Ordering};
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
+// pid of the process whose group reads the keyboard, 0 while the kernel
+// tasks do; only one side pops scancodes so no key is split between them
+static FOREGROUND: AtomicU64 = AtomicU64::new(0);
+// hands the keyboard to process `pid` and its descendants, or with None
+// back to the kernel tasks
+pub fn set_foreground(pid: Option<Pid>) {
+    FOREGROUND.store(pid.map_or(0, Pid::as_u64), Ordering::Relaxed);
+    WAKER.wake();
+}
+pub fn foreground() -> Option<Pid> {
+    match FOREGROUND.load(Ordering::Relaxed) {
+        0 => None,
+        raw => Some(Pid::from_raw(raw)),
+    }
+}
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
@@ -42,7 +59,14 @@ impl Stream for ScancodeStream {
         let queue = SCANCODE_QUEUE
             .try_get()
             .expect("scancode queue not initialized");
-        
+        // a process has the keyboard, `set_foreground` wakes us once it's back
+        if foreground().is_some() {
+            WAKER.register(cx.waker());
+            if foreground().is_some() {
+                return Poll::Pending;
+            }
+            WAKER.take();
+        }
         if let Some(scancode) = queue.pop() {
             return Poll::Ready(Some(scancode));
         }
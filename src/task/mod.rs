@@ -9,19 +9,57 @@ pub mod shell;
 pub mod time;
 pub mod status_bar;
 pub mod snake;
+pub mod join;
+pub mod cancel;
+pub use cancel::CancellationToken;
+pub use executor::{current_token, spawn, spawn_named, Spawner};
+pub use join::{Cancelled, JoinHandle};
+// scheduling class of a task, ready tasks of a higher class run first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+impl Priority {
+    pub const COUNT: usize = 3;
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "low" | "0" => Some(Priority::Low),
+            "normal" | "1" => Some(Priority::Normal),
+            "high" | "2" => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
 pub struct Task {
     id: TaskId,
     name: &'static str,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    priority: Priority,
+    token: CancellationToken,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
-        Task::new_named("task", future)
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task::new_named("task", Priority::Normal, future)
     }
-    pub fn new_named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new_named(
+        name: &'static str,
+        priority: Priority,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> Task {
         Task {
             id: TaskId::new(),
             name,
+            priority,
+            token: CancellationToken::new(),
             future: Box::pin(future),
         }
     }
@@ -31,6 +69,12 @@ impl Task {
     pub(crate) fn name(&self) -> &'static str {
         self.name
     }
+    pub(crate) fn priority(&self) -> Priority {
+        self.priority
+    }
+    pub(crate) fn token(&self) -> &CancellationToken {
+        &self.token
+    }
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
@@ -3,11 +3,13 @@ use crate::{println, print};
 use crate::vga_buffer::WRITER;
 use crate::task::keyboard::ScancodeStream;
 use crate::task::time::TickStream;
+use crate::task::Priority;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use futures_util::stream::StreamExt;
 use alloc::string::String;
 use alloc::vec::Vec;
 use x86_64::instructions::interrupts;
+use core::sync::atomic::Ordering;
 // main loop for the shell task
 pub async fn run() {
     let mut scancodes = ScancodeStream::new();
@@ -30,7 +32,16 @@ pub async fn run() {
                             }
                             '\n' => { 
                                 print!("\n");
-                                execute_command(&line_buffer).await;
+                                match line_buffer.trim_end().strip_suffix('&') {
+                                    Some(command) => run_in_background(String::from(command)),
+                                    None => {
+                                        execute_command(&line_buffer, true).await;
+                                        // a program may have had the keyboard, modifiers held
+                                        // back then were seen by its decoder
+                                        keyboard = Keyboard::new(ScancodeSet1::new(),
+                                            layouts::Us104Key, HandleControl::Ignore);
+                                    }
+                                }
                                 line_buffer.clear();
                                 print_prompt();
                             }
@@ -71,8 +82,19 @@ fn delete_char() {
         WRITER.lock().backspace();
     });
 }
-// executes the user entered command
-async fn execute_command(command: &str) {
+// runs a command as its own task so the prompt comes back right away
+fn run_in_background(command: String) {
+    let background = crate::task::spawn_named("background", Priority::Normal, async move {
+        execute_command(&command, false).await;
+    });
+    match background {
+        Some(handle) => println!("[{}] started", handle.id()),
+        None => println!("no executor to run it on"),
+    }
+}
+// executes the user entered command, a program run in the foreground gets
+// the keyboard until it exits
+async fn execute_command(command: &str, foreground: bool) {
     let mut parts = command.trim().split_whitespace();
     let cmd = match parts.next() {
         Some(s) => s,
@@ -86,12 +108,21 @@ async fn execute_command(command: &str) {
             println!("  clear      - Clear the screen");
             println!("  shutdown   - Exit QEMU");
             println!("  heap       - Show heap memory info");
+            println!("  frames     - Show physical frame usage");
+            println!("  slabs      - Show slab cache usage");
+            println!("  vmmap      - List mapped virtual memory");
+            println!("  memmap     - Show the physical memory map");
+            println!("  userdemo   - Run a demo program in ring 3");
+            println!("  run <prog> - Run a built in user program");
             println!("  alloc_test - Test heap allocation");
             println!("  snake      - Play Snake game!");
             println!("  panic      - Trigger a kernel panic");
-            println!("  ps         - List active tasks");
-            println!("  kill <id>  - Request a task to stop");
+            println!("  ps         - List processes, threads and tasks");
+            println!("  kill [-9] <id> - Ask a task to stop, -9 drops it at once");
+            println!("  nice <id> <prio> - Set a task's priority (low, normal, high)");
             println!("  sleep <n>  - Sleep for n timer ticks");
+            println!("  spin <n>   - Busy loop n ticks on a kernel thread");
+            println!("  <cmd> &    - Run a command as a background task");
         }
         "echo" => {
             let rest: String = parts.collect::<Vec<&str>>().join(" ");
@@ -107,8 +138,154 @@ async fn execute_command(command: &str) {
             crate::exit_qemu(crate::QemuExitCode::Success);
         }
         "heap" => {
-             println!("Heap Start: 0x{:x}", crate::allocator::HEAP_START);
-             println!("Heap Size:  {} bytes", crate::allocator::HEAP_SIZE);
+            let stats = crate::allocator::stats();
+            println!(
+                "Heap 0x{:x} ({}): {} KiB mapped, limit {} KiB",
+                stats.heap_start,
+                stats.backend.as_str(),
+                stats.heap_size / 1024,
+                crate::allocator::heap_limit() / 1024
+            );
+            println!(
+                "In use: {} bytes (peak {}), fallback used {} free {}",
+                stats.bytes_in_use,
+                stats.peak_bytes_in_use,
+                stats.fallback_used,
+                stats.fallback_free
+            );
+            if stats.backend == crate::allocator::HeapBackend::Guarded {
+                println!("Red zones intact in {} allocations", crate::allocator::check_heap());
+            }
+            if stats.backend != crate::allocator::HeapBackend::FixedSizeBlock {
+                println!("Live allocations: {}", stats.live_large);
+                return;
+            }
+            println!("BLOCK    LIVE   FREE");
+            for class in stats.size_classes.iter() {
+                println!(
+                    "{:>5} {:>7} {:>6}",
+                    class.block_size, class.live_allocations, class.free_blocks
+                );
+            }
+            println!("large {:>7}      -", stats.live_large);
+        }
+        "frames" => {
+            let Some(stats) = crate::memory::frame_stats() else {
+                println!("Frame allocator not initialized.");
+                return;
+            };
+            println!("Total: {:>6} frames ({} KiB)", stats.total_frames, stats.total_bytes() / 1024);
+            println!("Used:  {:>6} frames ({} KiB)", stats.used_frames, stats.used_bytes() / 1024);
+            println!("Free:  {:>6} frames ({} KiB)", stats.free_frames, stats.free_bytes() / 1024);
+        }
+        "slabs" => {
+            let caches = crate::allocator::slab::all_stats();
+            if caches.is_empty() {
+                println!("No slab caches in use.");
+                return;
+            }
+            println!("NAME         SIZE SLABS  USED  FREE    ALLOCS");
+            for cache in caches {
+                println!(
+                    "{:<12} {:>4} {:>5} {:>5} {:>5} {:>9}",
+                    cache.name,
+                    cache.object_size,
+                    cache.slabs,
+                    cache.objects_in_use,
+                    cache.objects_free,
+                    cache.total_allocations
+                );
+            }
+        }
+        "vmmap" => {
+            println!("VIRTUAL START     VIRTUAL END       PHYSICAL     PAGES SIZE PERM");
+            crate::memory::walker::for_each_mapping(|range| {
+                println!(
+                    "{:#016x}-{:#016x} {:#012x} {:>6} {:>4} {} {}",
+                    range.start.as_u64(),
+                    range.start.as_u64().wrapping_add(range.size),
+                    range.phys.as_u64(),
+                    range.pages(),
+                    range.page_size.as_str(),
+                    range.permissions(),
+                    range.marker()
+                );
+            });
+        }
+        "memmap" => {
+            use crate::memory::regions::{self, RegionClass};
+            println!("TYPE         START        END          SIZE KiB   USED KiB");
+            for region in crate::memory::regions() {
+                print!(
+                    "{:<12} {:#012x} {:#012x} {:>8} ",
+                    regions::region_type_name(region.region_type),
+                    region.range.start_addr(),
+                    region.range.end_addr(),
+                    regions::region_size(region) / 1024
+                );
+                match regions::region_class(region) {
+                    RegionClass::Usable => println!("{:>10}", regions::used_frames_in(region) * 4),
+                    _ => println!("{:>10}", "-"),
+                }
+            }
+            let totals = regions::region_totals();
+            println!(
+                "Usable {} KiB, kernel {} KiB, reserved {} KiB",
+                totals.usable / 1024,
+                totals.kernel / 1024,
+                totals.reserved / 1024
+            );
+        }
+        "userdemo" => {
+            match crate::usermode::run(&crate::usermode::DEMO_PROGRAM) {
+                Ok(crate::usermode::UserExit::Exit(value)) => println!("User program exited with {}", value),
+                Ok(exit) => println!("User program faulted: {:?}", exit),
+                Err(err) => println!("Could not map the user program: {:?}", err),
+            }
+        }
+        "run" => {
+            use crate::usermode::UserExit;
+            let Some(name) = parts.next() else {
+                println!("Usage: run <program> [args...]");
+                for (program, _) in crate::programs::PROGRAMS.iter() {
+                    println!("  {}", program);
+                }
+                return;
+            };
+            let Some(image) = crate::programs::find(name) else {
+                println!("Unknown program: {}", name);
+                return;
+            };
+            let argv: Vec<&str> = core::iter::once(name).chain(parts).collect();
+            let pid = match crate::process::spawn(name, image, &argv, crate::process::ENVIRONMENT, None) {
+                Ok(pid) => pid,
+                Err(err) => {
+                    println!("Could not load {}: {:?}", name, err);
+                    return;
+                }
+            };
+            if foreground {
+                crate::task::keyboard::set_foreground(Some(pid));
+            }
+            if let Err(err) = crate::process::start(pid) {
+                crate::task::keyboard::set_foreground(None);
+                println!("Could not start {}: {:?}", name, err);
+                return;
+            }
+            // the program runs on its own thread, the other tasks go on meanwhile
+            let exit = loop {
+                if let Some(exit) = crate::process::reap(pid) {
+                    break exit;
+                }
+                sleep_ticks(1).await;
+            };
+            if foreground {
+                crate::task::keyboard::set_foreground(None);
+            }
+            match exit {
+                UserExit::Exit(value) => println!("{} exited with {}", name, value),
+                exit => println!("{} faulted: {:?}", name, exit),
+            }
         }
         "alloc_test" => {
             let mut vec = Vec::new();
@@ -121,33 +298,75 @@ async fn execute_command(command: &str) {
             println!("Dropping vector (freeing memory)...");
         }
         "snake" => {
-             println!("Starting Snake Game... (Press 'q' or Enter to exit)");
-             crate::task::snake::run().await;
+            // still a kernel task, not a process: the game polls the keyboard and
+            // draws anywhere on the VGA buffer, which the system calls can't do
+            println!("Starting Snake Game... (Press 'q' or Enter to exit)");
+            let Some(game) = crate::task::spawn_named("snake", Priority::Normal, crate::task::snake::run()) else {
+                println!("no executor to run it on");
+                return;
+            };
+            if game.await.is_err() {
+                println!("snake was killed");
+            }
         }
         "panic" => {
             panic!("Manual panic triggered by user!");
         }
         "ps" => {
+            let processes = crate::process::snapshot_processes();
+            if !processes.is_empty() {
+                println!("PID  PPID STATE     TICKS SYSCALLS PAGES NAME");
+                for process in processes {
+                    print!("{:>3} ", process.pid.as_u64());
+                    match process.parent {
+                        Some(parent) => print!("{:>5} ", parent.as_u64()),
+                        None => print!("{:>5} ", "-"),
+                    }
+                    println!(
+                        "{:<9} {:>5} {:>8} {:>5} {}",
+                        process.state.as_str(),
+                        process.usage.ticks,
+                        process.usage.syscalls,
+                        process.usage.pages,
+                        process.name
+                    );
+                }
+            }
+            println!("TID  STATE     TICKS NAME");
+            for thread in crate::thread::snapshot_threads() {
+                println!(
+                    "{:>3}  {:<9} {:>5} {}",
+                    thread.id.as_u64(),
+                    thread.state.as_str(),
+                    thread.ticks,
+                    thread.name
+                );
+            }
             let tasks = crate::task::executor::snapshot_tasks();
             if tasks.is_empty() {
                 println!("No active tasks.");
                 return;
             }
 
-            println!("ID   STATE          POLLS NAME");
+            println!("ID   STATE         PRIO   POLLS NAME");
             for task in tasks {
                 println!(
-                    "{:>2}   {:<13} {:>5} {}",
+                    "{:>2}   {:<13} {:<6} {:>5} {}",
                     task.id,
                     task.state.as_str(),
+                    task.priority.as_str(),
                     task.poll_count,
                     task.name
                 );
             }
         }
         "kill" => {
-            let Some(raw_id) = parts.next() else {
-                println!("Usage: kill <task_id>");
+            let (force, raw_id) = match parts.next() {
+                Some("-9") => (true, parts.next()),
+                raw_id => (false, raw_id),
+            };
+            let Some(raw_id) = raw_id else {
+                println!("Usage: kill [-9] <task_id>");
                 return;
             };
 
@@ -159,7 +378,11 @@ async fn execute_command(command: &str) {
                 }
             };
 
-            match crate::task::executor::request_kill(task_id) {
+            let result = match force {
+                true => crate::task::executor::force_kill(task_id),
+                false => crate::task::executor::request_kill(task_id),
+            };
+            match result {
                 crate::task::executor::KillRequestResult::Queued => {
                     println!("Kill requested for task {}.", task_id);
                 }
@@ -171,6 +394,24 @@ async fn execute_command(command: &str) {
                 }
             }
         }
+        "nice" => {
+            let (Some(raw_id), Some(raw_priority)) = (parts.next(), parts.next()) else {
+                println!("Usage: nice <task_id> <low|normal|high>");
+                return;
+            };
+            let Ok(task_id) = raw_id.parse::<u64>() else {
+                println!("Invalid task id: '{}'", raw_id);
+                return;
+            };
+            let Some(priority) = crate::task::Priority::parse(raw_priority) else {
+                println!("Invalid priority: '{}'", raw_priority);
+                return;
+            };
+            match crate::task::executor::set_priority(task_id, priority) {
+                true => println!("Task {} now runs at {} priority.", task_id, priority.as_str()),
+                false => println!("Task {} not found.", task_id),
+            }
+        }
         "sleep" => {
             let Some(raw_ticks) = parts.next() else {
                 println!("Usage: sleep <ticks>");
@@ -193,6 +434,28 @@ async fn execute_command(command: &str) {
             sleep_ticks(ticks).await;
             println!("Awake.");
         }
+        "spin" => {
+            let ticks = match parts.next().map(str::parse::<u64>) {
+                Some(Ok(n)) if n > 0 => n,
+                _ => {
+                    println!("Usage: spin <ticks>");
+                    return;
+                }
+            };
+            // never yields, the timer has to take the cpu away from it
+            let spawned = crate::thread::spawn("spin", move || {
+                let start = crate::interrupts::TICK_COUNTER.load(Ordering::Relaxed) as u64;
+                let mut rounds = 0u64;
+                while (crate::interrupts::TICK_COUNTER.load(Ordering::Relaxed) as u64) < start + ticks {
+                    rounds = core::hint::black_box(rounds + 1);
+                }
+                println!("\nspin thread done after {} rounds", rounds);
+            });
+            match spawned {
+                Ok(id) => println!("Started thread {}.", id.as_u64()),
+                Err(err) => println!("Could not start a thread: {:?}", err),
+            }
+        }
         _ => {
             println!("Unknown command: '{}'", cmd);
             println!("Type 'help' to list commands.");
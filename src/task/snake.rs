@@ -27,7 +27,9 @@ struct Snake {
 }
 // main game loop for snake
 pub async fn run() {
-    
+    // a kill stops the game at the next tick, with the board cleared
+    let token = crate::task::current_token();
+    let cancelled = || token.as_ref().is_some_and(|token| token.is_cancelled());
     
     clear_play_area();
     
@@ -55,7 +57,10 @@ pub async fn run() {
     let mut score = 0;
     
     while let Some(_) = ticker.next().await {
-        
+        if cancelled() {
+            clear_play_area();
+            return;
+        }
         
         
         
@@ -158,6 +163,7 @@ pub async fn run() {
         if let Some(s) = keyboard::pop_scancode() {
              if s == 0x1C { break; } 
         }
+        if cancelled() { break; }
         
         ticker.next().await;
     }
@@ -12,13 +12,31 @@ pub async fn run() {
     let mut i = 0;
     
     
+    let token = crate::task::current_token();
+    // blanks the bar however the task ends after a kill
+    if let Some(token) = &token {
+        token.on_cancel(clear_bar);
+    }
     draw_bar(0);
     while let Some(count) = ticker.next().await {
+        if token.as_ref().is_some_and(|token| token.is_cancelled()) {
+            break;
+        }
         let spinner = chars[i % 4];
         i += 1;
         draw_status(count, spinner);
     }
 }
+// leaves the top row blank
+fn clear_bar() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let color = ColorCode::new(Color::White, Color::Black);
+        for col in 0..80 {
+            writer.write_at(0, col, b' ', color);
+        }
+    });
+}
 // draws the initial background of the bar
 fn draw_bar(initial_count: usize) {
      interrupts::without_interrupts(|| {
@@ -0,0 +1,351 @@
+// preemptive kernel threads: each has its own stack and saved registers and
+// the timer interrupt switches between the ready ones round robin; the boot
+// thread becomes thread 0 and keeps running the async executor
+use crate::interrupts::TICK_COUNTER;
+use crate::memory::{self, guard::{self, KernelStack}, vma::VmaError};
+use crate::{gdt, process, syscall, usermode};
+use alloc::{boxed::Box, collections::{BTreeMap, VecDeque}, vec::Vec};
+use core::arch::naked_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::PhysFrame,
+    VirtAddr,
+};
+const STACK_PAGES: u64 = 8;
+// what interrupts and system calls from ring 3 run on
+const PRIVILEGE_STACK_PAGES: u64 = 4;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+impl ThreadId {
+    const BOOT: ThreadId = ThreadId(0);
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    // until the tick in `wake_at`
+    Sleeping,
+    // waiting to be reaped, never runs again
+    Exited,
+}
+impl ThreadState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ThreadState::Ready => "Ready",
+            ThreadState::Running => "Running",
+            ThreadState::Sleeping => "Sleeping",
+            ThreadState::Exited => "Exited",
+        }
+    }
+}
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: ThreadState,
+    wake_at: u64,
+    ticks: u64,
+    // saved by `switch_stacks` while the thread is switched out
+    stack_pointer: u64,
+    // the rest of the cpu state that belongs to the thread
+    level_4: PhysFrame,
+    privilege_stack: VirtAddr,
+    user_return: u64,
+    process: u64,
+    // the boot thread runs on the stacks the kernel booted with
+    stacks: Option<(KernelStack, KernelStack)>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+}
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if let Some((stack, privilege_stack)) = self.stacks.take() {
+            unsafe {
+                guard::free_stack(stack);
+                guard::free_stack(privilege_stack);
+            }
+        }
+    }
+}
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadSnapshot {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: ThreadState,
+    pub ticks: u64,
+}
+struct Scheduler {
+    // boxed so that saved stack pointers stay put
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: VecDeque<ThreadId>,
+    current: ThreadId,
+    // runs when nothing else is ready, never queued
+    idle: ThreadId,
+    // tick the running thread was switched in at
+    switched_at: u64,
+}
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    interrupts::without_interrupts(|| f(SCHEDULER.lock().as_mut().expect("thread::init has not been called")))
+}
+fn ticks() -> u64 {
+    TICK_COUNTER.load(Ordering::Relaxed) as u64
+}
+// turns the running code into thread 0 and starts switching on timer
+// interrupts, needs the heap
+pub fn init() {
+    let boot = Box::new(Thread {
+        id: ThreadId::BOOT,
+        name: "main",
+        state: ThreadState::Running,
+        wake_at: 0,
+        ticks: 0,
+        stack_pointer: 0,
+        level_4: Cr3::read().0,
+        privilege_stack: gdt::boot_privilege_stack_top(),
+        user_return: 0,
+        process: 0,
+        stacks: None,
+        entry: None,
+    });
+    let idle = create("idle", Box::new(idle_loop)).expect("no memory for the idle thread");
+    let idle_id = idle.id;
+    let mut threads = BTreeMap::new();
+    threads.insert(ThreadId::BOOT, boot);
+    threads.insert(idle_id, idle);
+    interrupts::without_interrupts(|| {
+        *SCHEDULER.lock() = Some(Scheduler {
+            threads,
+            ready: VecDeque::new(),
+            current: ThreadId::BOOT,
+            idle: idle_id,
+            switched_at: ticks(),
+        });
+    });
+}
+// starts `f` on a new thread, which exits when `f` returns
+pub fn spawn(name: &'static str, f: impl FnOnce() + Send + 'static) -> Result<ThreadId, VmaError> {
+    reap();
+    let thread = create(name, Box::new(f))?;
+    let id = thread.id;
+    with_scheduler(|scheduler| {
+        scheduler.threads.insert(id, thread);
+        scheduler.ready.push_back(id);
+    });
+    Ok(id)
+}
+fn create(name: &'static str, entry: Box<dyn FnOnce() + Send>) -> Result<Box<Thread>, VmaError> {
+    let stack = guard::alloc_stack(name, STACK_PAGES)?;
+    let privilege_stack = match guard::alloc_stack(name, PRIVILEGE_STACK_PAGES) {
+        Ok(privilege_stack) => privilege_stack,
+        Err(err) => {
+            unsafe { guard::free_stack(stack) };
+            return Err(err);
+        }
+    };
+    // what `switch_stacks` pops: six callee saved registers and the return
+    // address, with a null return address above so `thread_start` sees an
+    // aligned stack like after a call
+    let frame: [u64; 8] = [0, 0, 0, 0, 0, 0, thread_start as *const () as u64, 0];
+    let stack_pointer = stack.top - 8 * frame.len() as u64;
+    unsafe { stack_pointer.as_mut_ptr::<[u64; 8]>().write(frame) };
+    Ok(Box::new(Thread {
+        id: ThreadId::new(),
+        name,
+        state: ThreadState::Ready,
+        wake_at: 0,
+        ticks: 0,
+        stack_pointer: stack_pointer.as_u64(),
+        level_4: memory::kernel_level_4_frame(),
+        privilege_stack: privilege_stack.top,
+        user_return: 0,
+        process: 0,
+        stacks: Some((stack, privilege_stack)),
+        entry: Some(entry),
+    }))
+}
+// the thread running right now
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| match SCHEDULER.lock().as_ref() {
+        Some(scheduler) => scheduler.current,
+        None => ThreadId::BOOT,
+    })
+}
+// called by the timer interrupt, gives up instead of spinning since it may
+// have interrupted a holder of the lock
+pub fn preempt() {
+    let Some(mut scheduler) = SCHEDULER.try_lock() else { return };
+    let Some(switch) = scheduler.as_mut().and_then(|s| s.reschedule(ThreadState::Ready)) else { return };
+    drop(scheduler);
+    unsafe { switch_stacks(switch.0, switch.1) };
+}
+// lets the other ready threads run first
+pub fn yield_now() {
+    block(ThreadState::Ready);
+}
+// blocks the running thread for at least `ticks` timer ticks
+pub fn sleep(ticks: u64) {
+    let wake_at = self::ticks() + ticks;
+    interrupts::without_interrupts(|| {
+        with_scheduler(|scheduler| {
+            let current = scheduler.current;
+            scheduler.threads.get_mut(&current).unwrap().wake_at = wake_at;
+        });
+        block(ThreadState::Sleeping);
+    });
+}
+// ends the running thread
+pub fn exit() -> ! {
+    block(ThreadState::Exited);
+    unreachable!("exited thread was switched back in");
+}
+// waits for thread `id` to exit and frees it
+pub fn join(id: ThreadId) {
+    loop {
+        let exited = with_scheduler(|scheduler| match scheduler.threads.get(&id) {
+            Some(thread) if thread.state == ThreadState::Exited => scheduler.threads.remove(&id).map(Some),
+            Some(_) => None,
+            None => Some(None),
+        });
+        if let Some(thread) = exited {
+            drop(thread);
+            return;
+        }
+        yield_now();
+    }
+}
+// switches away with the running thread put into `state`
+fn block(state: ThreadState) {
+    interrupts::without_interrupts(|| {
+        let switch = with_scheduler(|scheduler| scheduler.reschedule(state));
+        if let Some((save, load)) = switch {
+            unsafe { switch_stacks(save, load) };
+        }
+    });
+}
+// frees the stacks of exited threads, never called on one of them
+fn reap() {
+    let exited: Vec<Box<Thread>> = with_scheduler(|scheduler| {
+        let ids: Vec<ThreadId> = scheduler
+            .threads
+            .values()
+            .filter(|thread| thread.state == ThreadState::Exited)
+            .map(|thread| thread.id)
+            .collect();
+        ids.iter().filter_map(|id| scheduler.threads.remove(id)).collect()
+    });
+    drop(exited);
+}
+pub fn snapshot_threads() -> Vec<ThreadSnapshot> {
+    with_scheduler(|scheduler| {
+        let now = ticks();
+        scheduler
+            .threads
+            .values()
+            .map(|thread| ThreadSnapshot {
+                id: thread.id,
+                name: thread.name,
+                state: thread.state,
+                ticks: match thread.id == scheduler.current {
+                    true => thread.ticks + now - scheduler.switched_at,
+                    false => thread.ticks,
+                },
+            })
+            .collect()
+    })
+}
+impl Scheduler {
+    // picks the next thread and swaps in its cpu state, returning where to
+    // save the current stack pointer and which one to load; None keeps the
+    // running thread, which is only possible if it stays ready
+    fn reschedule(&mut self, state: ThreadState) -> Option<(*mut u64, u64)> {
+        let now = ticks();
+        for thread in self.threads.values_mut() {
+            if thread.state == ThreadState::Sleeping && thread.wake_at <= now {
+                thread.state = ThreadState::Ready;
+                self.ready.push_back(thread.id);
+            }
+        }
+        let current = self.current;
+        let next = match self.ready.pop_front() {
+            Some(next) => next,
+            None if state == ThreadState::Ready => return None,
+            None => self.idle,
+        };
+        if next == current {
+            return None;
+        }
+        if state == ThreadState::Ready && current != self.idle {
+            self.ready.push_back(current);
+        }
+        let incoming = self.threads.get_mut(&next).unwrap();
+        incoming.state = ThreadState::Running;
+        let load = incoming.stack_pointer;
+        let user_return = usermode::swap_saved_stack(incoming.user_return);
+        let process = process::swap_current(incoming.process);
+        gdt::set_privilege_stack_top(incoming.privilege_stack);
+        syscall::set_kernel_stack(incoming.privilege_stack);
+        let level_4 = Cr3::read().0;
+        if level_4 != incoming.level_4 {
+            unsafe { Cr3::write(incoming.level_4, Cr3Flags::empty()) };
+        }
+        let outgoing = self.threads.get_mut(&current).unwrap();
+        outgoing.state = state;
+        outgoing.ticks += now - self.switched_at;
+        outgoing.user_return = user_return;
+        outgoing.process = process;
+        outgoing.level_4 = level_4;
+        let save = &mut outgoing.stack_pointer as *mut u64;
+        self.current = next;
+        self.switched_at = now;
+        Some((save, load))
+    }
+}
+fn idle_loop() {
+    loop {
+        reap();
+        interrupts::enable_and_hlt();
+    }
+}
+// where new threads first get switched to, with interrupts still off
+extern "C" fn thread_start() -> ! {
+    let entry = with_scheduler(|scheduler| {
+        let current = scheduler.current;
+        scheduler.threads.get_mut(&current).unwrap().entry.take()
+    });
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit()
+}
+// saves the callee saved registers on the current stack and its pointer in
+// `save`, then pops them off the stack at `load` and returns there
+#[unsafe(naked)]
+unsafe extern "C" fn switch_stacks(save: *mut u64, load: u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
@@ -0,0 +1,222 @@
+// running code in ring 3 and getting back into the kernel through a trap
+use crate::gdt;
+use crate::memory::{address_space::{self, AddressSpace}, vma::{Vma, VmaError, VmaKind}};
+use core::arch::naked_asm;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+pub const USER_STACK_SIZE: u64 = 4096 * 4;
+// sums 1..=10 with the partial values pushed on the user stack and exits
+// with the result (55) through the exit system call
+pub const DEMO_PROGRAM: [u8; 32] = [
+    0xb9, 0x0a, 0x00, 0x00, 0x00, //     mov ecx, 10
+    0x51, //                          1: push rcx
+    0xe2, 0xfd, //                       loop 1b
+    0xb9, 0x0a, 0x00, 0x00, 0x00, //     mov ecx, 10
+    0x31, 0xc0, //                       xor eax, eax
+    0x5a, //                          2: pop rdx
+    0x48, 0x01, 0xd0, //                 add rax, rdx
+    0xe2, 0xfa, //                       loop 2b
+    0x89, 0xc7, //                       mov edi, eax
+    0xb8, 0x03, 0x00, 0x00, 0x00, //     mov eax, 3 (exit)
+    0x0f, 0x05, //                       syscall
+    0x0f, 0x0b, //                       ud2
+];
+// how a user program got back into the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    // the exit system call with this value
+    Exit(u64),
+    PageFault(VirtAddr),
+    GeneralProtection,
+    InvalidOpcode,
+}
+const EXIT: u64 = 0;
+const PAGE_FAULT: u64 = 1;
+const GENERAL_PROTECTION: u64 = 2;
+const INVALID_OPCODE: u64 = 3;
+impl UserExit {
+    // the status a waiting parent sees: the exit value, or 128 plus the
+    // exception vector for faults
+    pub fn status(self) -> u64 {
+        match self {
+            UserExit::Exit(value) => value,
+            UserExit::PageFault(_) => 128 + 14,
+            UserExit::GeneralProtection => 128 + 13,
+            UserExit::InvalidOpcode => 128 + 6,
+        }
+    }
+}
+// the registers of a program while it is in the kernel, in the order the
+// system call entries push them, ending with an interrupt return frame
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct UserRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+// interrupts on, everything else cleared
+const INITIAL_FLAGS: u64 = 0x202;
+// the status flags and direction, user code may keep those
+const USER_FLAGS: u64 = 0xcd5;
+impl UserRegisters {
+    // a program about to start at `entry`, nothing of the kernel leaks
+    // through the registers
+    pub fn new(entry: VirtAddr, stack_pointer: VirtAddr) -> Self {
+        UserRegisters {
+            rip: entry.as_u64(),
+            rflags: INITIAL_FLAGS,
+            rsp: stack_pointer.as_u64(),
+            ..UserRegisters::default()
+        }
+    }
+}
+// returned in rax and rdx by `enter_user`
+#[repr(C)]
+struct RawExit {
+    kind: u64,
+    value: u64,
+}
+// kernel stack pointer saved by `enter_user`, swapped on thread switches
+static mut KERNEL_STACK_POINTER: u64 = 0;
+// copies `code` into a fresh address space, runs it in ring 3 on its own
+// stack until it exits or faults, then throws the address space away
+pub fn run(code: &[u8]) -> Result<UserExit, VmaError> {
+    let mut space = AddressSpace::new()?;
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let text = space.map("user code", code.len() as u64, user, VmaKind::Anonymous)?;
+    let stack = map_stack(&mut space)?;
+    space.write_bytes(text.start, code)?;
+    Ok(unsafe { enter_space(&space, text.start, stack.end) })
+}
+// maps a user stack with a guard page below it
+pub fn map_stack(space: &mut AddressSpace) -> Result<Vma, VmaError> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE;
+    space.map("user stack", USER_STACK_SIZE + 4096, flags, VmaKind::Stack)
+}
+// like `enter`, switching to `space` for the time the program runs; `entry`
+// and the stack below `stack_pointer` must be mapped for ring 3 in `space`
+pub unsafe fn enter_space(space: &AddressSpace, entry: VirtAddr, stack_pointer: VirtAddr) -> UserExit {
+    space.activate();
+    let exit = unsafe { enter(entry, stack_pointer) };
+    address_space::activate_kernel();
+    exit
+}
+// jumps to `entry` in ring 3 and returns once the program exits or faults;
+// `entry` and the stack below `stack_pointer` must be mapped for ring 3 in
+// the active address space
+pub unsafe fn enter(entry: VirtAddr, stack_pointer: VirtAddr) -> UserExit {
+    unsafe { enter_registers(&UserRegisters::new(entry, stack_pointer)) }
+}
+// like `enter` but starts with every register taken from `registers`, only
+// the segments and the privileged flags are forced to ring 3 values; `rip`
+// and the stack below `rsp` must be mapped for ring 3 in the active address space
+pub unsafe fn enter_registers(registers: &UserRegisters) -> UserExit {
+    let (code_selector, data_selector) = gdt::user_selectors();
+    let mut registers = *registers;
+    registers.cs = code_selector.0 as u64;
+    registers.ss = data_selector.0 as u64;
+    registers.rflags = (registers.rflags & USER_FLAGS) | INITIAL_FLAGS;
+    let raw = unsafe { enter_user(&registers) };
+    match raw.kind {
+        EXIT => UserExit::Exit(raw.value),
+        PAGE_FAULT => UserExit::PageFault(VirtAddr::new(raw.value)),
+        GENERAL_PROTECTION => UserExit::GeneralProtection,
+        _ => UserExit::InvalidOpcode,
+    }
+}
+// exchanges the saved kernel stack pointer, for the scheduler
+pub(crate) fn swap_saved_stack(raw: u64) -> u64 {
+    unsafe {
+        let saved = KERNEL_STACK_POINTER;
+        KERNEL_STACK_POINTER = raw;
+        saved
+    }
+}
+// called for the exit system call and for faults raised in ring 3, drops
+// the program and returns from `run`
+pub fn leave(exit: UserExit) -> ! {
+    let (kind, value) = match exit {
+        UserExit::Exit(value) => (EXIT, value),
+        UserExit::PageFault(addr) => (PAGE_FAULT, addr.as_u64()),
+        UserExit::GeneralProtection => (GENERAL_PROTECTION, 0),
+        UserExit::InvalidOpcode => (INVALID_OPCODE, 0),
+    };
+    unsafe { leave_user(kind, value) }
+}
+// saves the callee saved registers and flags on the kernel stack, then
+// copies `registers` below them and irets into ring 3 with all of them
+#[unsafe(naked)]
+unsafe extern "C" fn enter_user(registers: *const UserRegisters) -> RawExit {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "pushfq",
+        "mov [rip + {saved}], rsp",
+        "sub rsp, {size}",
+        "mov rsi, rdi",
+        "mov rdi, rsp",
+        "mov ecx, {words}",
+        "rep movsq",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+        saved = sym KERNEL_STACK_POINTER,
+        size = const core::mem::size_of::<UserRegisters>(),
+        words = const core::mem::size_of::<UserRegisters>() / 8,
+    )
+}
+// switches back to the stack saved by `enter_user` and returns from it
+#[unsafe(naked)]
+unsafe extern "C" fn leave_user(kind: u64, value: u64) -> ! {
+    naked_asm!(
+        "mov rsp, [rip + {saved}]",
+        "mov rax, rdi",
+        "mov rdx, rsi",
+        "popfq",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        saved = sym KERNEL_STACK_POINTER,
+    )
+}
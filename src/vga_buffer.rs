@@ -82,7 +82,11 @@ impl Writer {
     }
     // writes a string to the screen
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
+        self.write_bytes(s.as_bytes());
+    }
+    // writes raw bytes, anything unprintable shows up as a block
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
             match byte {
                 
                 0x20..=0x7e | b'\n' => self.write_byte(byte),
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::memory::{self, cow, vma::{self, VmaKind}, GlobalFrameAllocator};
+use x86_64::structures::paging::{
+    mapper::{MappedFrame, TranslateResult},
+    PageTableFlags, PhysFrame, Translate,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+fn mapping(addr: VirtAddr) -> (PhysFrame, PageTableFlags) {
+    memory::with_mapper(|mapper| match mapper.translate(addr) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+        _ => panic!("{:?} is not mapped", addr),
+    })
+}
+
+fn read(addr: VirtAddr) -> u64 {
+    unsafe { addr.as_ptr::<u64>().read_volatile() }
+}
+
+fn write(addr: VirtAddr, value: u64) {
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(value) }
+}
+
+#[test_case]
+fn snapshot_shares_frames_until_written() {
+    let area = vma::map("original", 2 * 4096, FLAGS, VmaKind::Anonymous).unwrap();
+    write(area.start, 1);
+    write(area.start + 4096u64, 2);
+
+    let copy = cow::snapshot(area.start, "snapshot").unwrap();
+    let (frame, flags) = mapping(area.start);
+    assert_eq!(mapping(copy.start).0, frame);
+    assert!(flags.contains(cow::COW) && !flags.contains(PageTableFlags::WRITABLE));
+    assert_eq!(GlobalFrameAllocator.references(frame), 2);
+    assert_eq!(read(copy.start), 1);
+    assert_eq!(read(copy.start + 4096u64), 2);
+
+    // writing the original makes it a private copy, the snapshot keeps the old data
+    write(area.start, 10);
+    assert_eq!(read(area.start), 10);
+    assert_eq!(read(copy.start), 1);
+    assert_ne!(mapping(area.start).0, frame);
+    assert_eq!(GlobalFrameAllocator.references(frame), 1);
+
+    // the last reference takes the frame back without copying
+    write(copy.start, 20);
+    let (copy_frame, copy_flags) = mapping(copy.start);
+    assert_eq!(copy_frame, frame);
+    assert!(copy_flags.contains(PageTableFlags::WRITABLE) && !copy_flags.contains(cow::COW));
+    assert_eq!(read(area.start), 10);
+
+    let shared = mapping(area.start + 4096u64).0;
+    unsafe { vma::unmap(area.start).unwrap() };
+    assert_eq!(GlobalFrameAllocator.references(shared), 1);
+    assert_eq!(read(copy.start + 4096u64), 2);
+    unsafe { vma::unmap(copy.start).unwrap() };
+    assert_eq!(GlobalFrameAllocator.references(shared), 0);
+}
@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::memory::{self, vma::{self, VmaKind}};
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::with_mapper(|mapper| mapper.translate_addr(addr).is_some())
+}
+
+fn free_frames() -> usize {
+    memory::frame_stats().unwrap().free_frames
+}
+
+#[test_case]
+fn lazy_area_is_backed_on_first_touch() {
+    let before = free_frames();
+    let area = vma::map("lazy", 64 * 4096, FLAGS, VmaKind::Lazy).unwrap();
+    assert_eq!(free_frames(), before);
+    assert!(!is_mapped(area.start));
+
+    let value: *mut u64 = (area.start + 5 * 4096u64 + 8u64).as_mut_ptr();
+    assert_eq!(unsafe { value.read_volatile() }, 0);
+    unsafe { value.write_volatile(0xdead_beef) };
+    assert_eq!(unsafe { value.read_volatile() }, 0xdead_beef);
+    assert!(is_mapped(area.start + 5 * 4096u64));
+    assert!(!is_mapped(area.start + 4 * 4096u64));
+    assert!(!is_mapped(area.start + 6 * 4096u64));
+    // page tables created on the way stay around, only the data frame comes back
+    let touched = free_frames();
+    assert!(touched < before);
+    unsafe { vma::unmap(area.start).unwrap() };
+    assert_eq!(free_frames(), touched + 1);
+}
+
+#[test_case]
+fn lazy_area_backs_every_touched_page() {
+    let pages = 32;
+    let area = vma::map("lazy", pages * 4096, FLAGS, VmaKind::Lazy).unwrap();
+    for page in 0..pages {
+        let ptr: *mut u64 = (area.start + page * 4096).as_mut_ptr();
+        unsafe { ptr.write_volatile(page) };
+    }
+    for page in 0..pages {
+        let ptr: *const u64 = (area.start + page * 4096).as_ptr();
+        assert_eq!(unsafe { ptr.read_volatile() }, page);
+    }
+    unsafe { vma::unmap(area.start).unwrap() };
+}
@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::allocator;
+use toy_os::elf::{self, ElfError};
+use toy_os::memory::{self, address_space, AddressSpace};
+use toy_os::programs;
+use toy_os::usermode::UserExit;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn hello() -> &'static [u8] {
+    programs::find("hello").unwrap()
+}
+
+fn free_frames() -> usize {
+    memory::frame_stats().unwrap().free_frames
+}
+
+#[test_case]
+fn hello_exits_with_argc() {
+    let before = free_frames();
+    let exit = elf::run(hello(), &["hello", "a", "b"], &["TERM=vga"]).unwrap();
+    assert_eq!(exit, UserExit::Exit(3));
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn segments_get_their_permissions() {
+    let mut space = AddressSpace::new().unwrap();
+    elf::load(&mut space, programs::find("keys").unwrap(), &["keys"], &[]).unwrap();
+    let areas: Vec<_> = space.areas().filter(|area| area.name != "boot").collect();
+    let find = |name| areas.iter().find(|area| area.name == name).unwrap().flags;
+    let code = find("user code");
+    assert!(!code.contains(PageTableFlags::WRITABLE) && !code.contains(PageTableFlags::NO_EXECUTE));
+    let rodata = find("user rodata");
+    assert!(!rodata.contains(PageTableFlags::WRITABLE) && rodata.contains(PageTableFlags::NO_EXECUTE));
+    let data = find("user data");
+    assert!(data.contains(PageTableFlags::WRITABLE) && data.contains(PageTableFlags::NO_EXECUTE));
+    assert!(areas.iter().all(|area| area.flags.contains(PageTableFlags::USER_ACCESSIBLE)));
+}
+
+#[test_case]
+fn stack_holds_argv_and_envp() {
+    let mut space = AddressSpace::new().unwrap();
+    let loaded = elf::load(&mut space, hello(), &["hello", "world"], &["TERM=vga"]).unwrap();
+    assert!(loaded.stack_pointer.is_aligned(16u64));
+    // the kernel can read user pages once their address space is active
+    space.activate();
+    let words: *const u64 = loaded.stack_pointer.as_ptr();
+    let string = |ptr: u64| unsafe {
+        let start = ptr as *const u8;
+        let len = (0..).take_while(|&i| *start.add(i) != 0).count();
+        core::str::from_utf8(core::slice::from_raw_parts(start, len)).unwrap()
+    };
+    unsafe {
+        assert_eq!(*words, 2);
+        assert_eq!(string(*words.add(1)), "hello");
+        assert_eq!(string(*words.add(2)), "world");
+        assert_eq!(*words.add(3), 0);
+        assert_eq!(string(*words.add(4)), "TERM=vga");
+        assert_eq!(*words.add(5), 0);
+        // AT_NULL
+        assert_eq!((*words.add(6), *words.add(7)), (0, 0));
+    }
+    address_space::activate_kernel();
+}
+
+#[test_case]
+fn broken_images_are_rejected() {
+    let before = free_frames();
+    let load = |image: &[u8]| {
+        let mut space = AddressSpace::new().unwrap();
+        elf::load(&mut space, image, &["hello"], &[]).err().unwrap()
+    };
+    let patched = |offset: usize, bytes: &[u8]| {
+        let mut image = hello().to_vec();
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+        load(&image)
+    };
+    assert!(matches!(load(&hello()[..32]), ElfError::Truncated));
+    assert!(matches!(patched(0, b"\x7fELG"), ElfError::BadMagic));
+    assert!(matches!(patched(4, &[1]), ElfError::NotElf64));
+    assert!(matches!(patched(16, &3u16.to_le_bytes()), ElfError::NotExecutable));
+    assert!(matches!(patched(18, &3u16.to_le_bytes()), ElfError::WrongMachine));
+    // entry point into the rodata segment
+    assert!(matches!(patched(24, &0x1000_0040_1000u64.to_le_bytes()), ElfError::BadEntry));
+    // first program header: make the code writable, then move it into the kernel
+    assert!(matches!(patched(64 + 4, &7u32.to_le_bytes()), ElfError::WritableAndExecutable));
+    assert!(matches!(patched(64 + 16, &0x4000_0000_0000u64.to_le_bytes()), ElfError::OutsideUserWindow));
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn segments_sharing_a_page_are_merged() {
+    // moves the rodata segment of hello right behind its code, into the same page
+    let mut image = hello().to_vec();
+    let rodata = 64 + 56;
+    image[rodata + 16..rodata + 32].copy_from_slice(&[0x1000_0040_0100u64.to_le_bytes(); 2].concat());
+    let mut space = AddressSpace::new().unwrap();
+    elf::load(&mut space, &image, &["hello"], &[]).unwrap();
+    let areas: Vec<_> = space.areas().filter(|area| area.name != "boot" && area.name != "user stack").collect();
+    assert_eq!(areas.len(), 1);
+    assert_eq!((areas[0].name, areas[0].start.as_u64(), areas[0].size()), ("user code", 0x1000_0040_0000, 4096));
+    space.activate();
+    let code = unsafe { core::slice::from_raw_parts(0x1000_0040_0000 as *const u8, 0x73) };
+    let data = unsafe { core::slice::from_raw_parts(0x1000_0040_0100 as *const u8, 0x24) };
+    assert!(code == &image[0x1000..0x1073] && data == &image[0x2000..0x2024]);
+    address_space::activate_kernel();
+
+    // a writable segment in the code page would make it writable and executable
+    image[rodata + 4..rodata + 8].copy_from_slice(&6u32.to_le_bytes());
+    let mut space = AddressSpace::new().unwrap();
+    assert!(matches!(elf::load(&mut space, &image, &["hello"], &[]), Err(ElfError::WritableAndExecutable)));
+}
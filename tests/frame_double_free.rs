@@ -0,0 +1,32 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::memory;
+use toy_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("frame_double_free::double_free_panics...\t");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frames = unsafe { memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset) };
+    let frame: PhysFrame = frames.allocate_frame().unwrap();
+    unsafe {
+        frames.deallocate_frame(frame);
+        frames.deallocate_frame(frame);
+    }
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -6,27 +6,18 @@
 
 extern crate alloc;
 
+mod heap_common;
+
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use toy_os::allocator::HEAP_SIZE;
+use toy_os::allocator::HeapBackend;
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use toy_os::allocator;
-    use toy_os::memory::{self, BootInfoFrameAllocator};
-    use x86_64::VirtAddr;
-
-    toy_os::init();
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
+    heap_common::init(boot_info, HeapBackend::FixedSizeBlock);
 
     test_main();
     loop {}
@@ -38,37 +29,103 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 #[test_case]
-fn simple_allocation() {
-    let heap_value_1 = Box::new(41);
-    let heap_value_2 = Box::new(13);
-    assert_eq!(*heap_value_1, 41);
-    assert_eq!(*heap_value_2, 13);
+fn uses_fixed_size_block_backend() {
+    assert_eq!(toy_os::allocator::backend(), HeapBackend::FixedSizeBlock);
 }
 
 #[test_case]
-fn large_vec() {
-    let n = 1000;
-    let mut vec = Vec::new();
-    for i in 0..n {
-        vec.push(i);
-    }
-    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+fn stats_track_live_allocations() {
+    use toy_os::allocator;
+    let before = allocator::stats();
+    let value = Box::new(7u64);
+    let during = allocator::stats();
+    assert_eq!(during.size_classes[0].live_allocations, before.size_classes[0].live_allocations + 1);
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+    drop(value);
+    let after = allocator::stats();
+    assert_eq!(after.size_classes[0].live_allocations, before.size_classes[0].live_allocations);
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
 }
 
 #[test_case]
-fn many_boxes() {
-    for i in 0..HEAP_SIZE {
-        let x = Box::new(i);
-        assert_eq!(*x, i);
+fn trim_recovers_blocks_across_size_classes() {
+    use toy_os::allocator;
+    allocator::trim();
+    let before = allocator::stats();
+    let small: Vec<Box<[u8; 64]>> = (0..200).map(|_| Box::new([1; 64])).collect();
+    let medium: Vec<Box<[u8; 256]>> = (0..200).map(|_| Box::new([2; 256])).collect();
+    drop(small);
+    drop(medium);
+    let released = allocator::trim();
+    let after = allocator::stats();
+    assert!(released > 0);
+    for class in after.size_classes.iter() {
+        assert_eq!(class.free_blocks, 0);
     }
+    assert_eq!(after.fallback_used, before.fallback_used);
+    let big = Box::new([3u8; 4096]);
+    assert_eq!(big[4095], 3);
 }
 
 #[test_case]
-fn many_boxes_long_lived() {
-    let long_lived = Box::new(1); // new
-    for i in 0..HEAP_SIZE {
-        let x = Box::new(i);
-        assert_eq!(*x, i);
-    }
-    assert_eq!(*long_lived, 1); // new
+fn slab_cache_reuses_slots() {
+    use toy_os::allocator::slab::{SlabCache, SlabInfo};
+    static CACHE: SlabCache<[u64; 4]> = SlabCache::with_constructor("test", || [7; 4]);
+    let per_slab = {
+        let first = CACHE.alloc_constructed().unwrap();
+        assert_eq!(*first, [7; 4]);
+        CACHE.stats().objects_per_slab
+    };
+    let objects: Vec<_> = (0..per_slab * 3)
+        .map(|i| CACHE.alloc([i as u64; 4]).unwrap())
+        .collect();
+    assert_eq!(objects[per_slab + 1][0], (per_slab + 1) as u64);
+    let stats = CACHE.stats();
+    assert_eq!(stats.objects_in_use, per_slab * 3);
+    assert!(stats.slabs >= 3);
+    drop(objects);
+    let stats = CACHE.stats();
+    assert_eq!(stats.objects_in_use, 0);
+    assert_eq!(stats.slabs, 1);
+}
+
+#[test_case]
+fn slab_objects_are_constructed_with_their_slab() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use toy_os::allocator::slab::{SlabCache, SlabInfo};
+    static BUILT: AtomicUsize = AtomicUsize::new(0);
+    static CACHE: SlabCache<[u64; 2]> = SlabCache::with_constructor("built", || {
+        BUILT.fetch_add(1, Ordering::Relaxed);
+        [1, 2]
+    });
+    let mut first = CACHE.alloc_constructed().unwrap();
+    let per_slab = CACHE.stats().objects_per_slab;
+    assert_eq!(BUILT.load(Ordering::Relaxed), per_slab);
+    assert_eq!(*first, [1, 2]);
+    first[0] = 5;
+    drop(first);
+    // objects come back the way their last user left them
+    let again = CACHE.alloc_constructed().unwrap();
+    assert_eq!(*again, [5, 2]);
+    assert_eq!(BUILT.load(Ordering::Relaxed), per_slab);
+}
+
+#[test_case]
+fn freed_slabs_drop_objects_that_free_into_their_cache() {
+    use toy_os::allocator::slab::{SlabBox, SlabCache, SlabInfo};
+    struct Link(Option<SlabBox<Link>>);
+    static CACHE: SlabCache<Link> = SlabCache::with_constructor("links", || Link(None));
+    let first = CACHE.alloc_constructed().unwrap();
+    let per_slab = CACHE.stats().objects_per_slab;
+    let mut links: Vec<_> = core::iter::once(first)
+        .chain((0..per_slab).map(|_| CACHE.alloc_constructed().unwrap()))
+        .collect();
+    // the only object of the second slab ends up held by an object of the first
+    let last = links.pop().unwrap();
+    links[0].0 = Some(last);
+    assert_eq!(CACHE.stats().slabs, 2);
+    // emptying the first slab drops its objects, which frees `last`
+    drop(links);
+    let stats = CACHE.stats();
+    assert_eq!((stats.slabs, stats.objects_in_use), (1, 0));
 }
@@ -0,0 +1,32 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod heap_common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::allocator::HeapBackend;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    heap_common::init(boot_info, HeapBackend::Bump);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn uses_bump_backend() {
+    assert_eq!(toy_os::allocator::backend(), HeapBackend::Bump);
+}
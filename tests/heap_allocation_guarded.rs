@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod heap_common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::allocator::HeapBackend;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    heap_common::init(boot_info, HeapBackend::Guarded);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn uses_guarded_backend() {
+    assert_eq!(toy_os::allocator::backend(), HeapBackend::Guarded);
+}
+
+#[test_case]
+fn allocations_get_their_own_pages() {
+    use alloc::boxed::Box;
+    use toy_os::allocator::{self, guarded};
+    let value = Box::new(7u64);
+    let addr = &*value as *const u64 as usize;
+    assert!((guarded::GUARDED_HEAP_START..guarded::GUARDED_HEAP_START + guarded::GUARDED_HEAP_SIZE).contains(&addr));
+    assert!(allocator::check_heap() >= 1);
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod heap_common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::allocator::HeapBackend;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    heap_common::init(boot_info, HeapBackend::LinkedList);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn uses_linked_list_backend() {
+    assert_eq!(toy_os::allocator::backend(), HeapBackend::LinkedList);
+}
+
+#[test_case]
+fn interleaved_frees_coalesce() {
+    use alloc::vec::Vec;
+    use core::alloc::{GlobalAlloc, Layout};
+    use toy_os::allocator::linked_list::{FitStrategy, LinkedListAllocator};
+    use toy_os::allocator::Locked;
+
+    const ARENA_SIZE: usize = 16 * 1024;
+    #[repr(align(16))]
+    struct Arena([u8; ARENA_SIZE]);
+    static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+    for strategy in [FitStrategy::FirstFit, FitStrategy::BestFit] {
+        let heap = Locked::new(LinkedListAllocator::with_strategy(strategy));
+        unsafe { heap.lock().init(&raw mut ARENA.0 as usize, ARENA_SIZE) };
+        let small = Layout::from_size_align(48, 8).unwrap();
+        let large = Layout::from_size_align(200, 32).unwrap();
+        let mut blocks = Vec::new();
+        for i in 0..64 {
+            let layout = if i % 2 == 0 { small } else { large };
+            let ptr = unsafe { heap.alloc(layout) };
+            assert!(!ptr.is_null());
+            blocks.push((ptr, layout));
+        }
+        // free every other block first so the holes can't merge yet
+        for &(ptr, layout) in blocks.iter().step_by(2) {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+        assert!(heap.lock().free_regions() > 1);
+        for &(ptr, layout) in blocks.iter().skip(1).step_by(2) {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+        assert_eq!(heap.lock().free_regions(), 1);
+
+        let whole = Layout::from_size_align(ARENA_SIZE, 16).unwrap();
+        let ptr = unsafe { heap.alloc(whole) };
+        assert!(!ptr.is_null());
+        unsafe { heap.dealloc(ptr, whole) };
+    }
+}
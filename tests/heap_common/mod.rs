@@ -0,0 +1,64 @@
+// heap test cases shared by every allocator backend
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::BootInfo;
+use toy_os::allocator::{self, HeapBackend, HEAP_SIZE};
+
+pub fn init(boot_info: &'static BootInfo, backend: HeapBackend) {
+    use toy_os::memory;
+    use x86_64::VirtAddr;
+
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap_with(backend)
+        .expect("heap initialization failed");
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn many_boxes() {
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1); // new
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1); // new
+}
+
+#[test_case]
+fn heap_grows_past_initial_size() {
+    let size = HEAP_SIZE * 4;
+    let mut big = Vec::<u8>::with_capacity(size);
+    big.resize(size, 0xab);
+    assert_eq!(big[0], 0xab);
+    assert_eq!(big[size - 1], 0xab);
+}
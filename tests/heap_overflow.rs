@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use toy_os::allocator::{self, HeapBackend};
+use toy_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toy_os::memory;
+    use x86_64::VirtAddr;
+
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    toy_os::gdt::protect_stacks();
+    allocator::init_heap_with(HeapBackend::Guarded)
+        .expect("heap initialization failed");
+
+    serial_print!("heap_overflow::write_past_end...\t");
+    let buffer = Box::new([0u8; 16]);
+    let ptr = Box::into_raw(buffer) as *mut u8;
+    // one byte past the end lands in the guard page
+    unsafe { ptr.add(16).write_volatile(1) };
+
+    serial_println!("[write past the end was not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// collects the panic message so the report can be checked
+struct Message {
+    buffer: [u8; 256],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = (self.len + s.len()).min(self.buffer.len());
+        self.buffer[self.len..end].copy_from_slice(&s.as_bytes()[..end - self.len]);
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message { buffer: [0; 256], len: 0 };
+    let _ = write!(message, "{}", info.message());
+    let message = core::str::from_utf8(&message.buffer[..message.len]).unwrap_or("");
+    if message.starts_with("HEAP OVERFLOW") && message.contains("offset 16") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::allocator::{self, HEAP_START};
+use toy_os::memory::{self, vma::{self, VmaKind}};
+use x86_64::structures::paging::{
+    mapper::{MappedFrame, TranslateResult},
+    PageTableFlags, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+const HUGE: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::HUGE_PAGE);
+const MIB: u64 = 1024 * 1024;
+
+fn mapped_frame(addr: VirtAddr) -> Option<MappedFrame> {
+    memory::with_mapper(|mapper| match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, .. } => Some(frame),
+        _ => None,
+    })
+}
+
+fn free_frames() -> usize {
+    memory::frame_stats().unwrap().free_frames
+}
+
+#[test_case]
+fn anonymous_area_uses_2mib_pages() {
+    let area = vma::map("huge", 4 * MIB, HUGE, VmaKind::Anonymous).unwrap();
+    assert!(area.start.is_aligned(2 * MIB));
+    assert!(matches!(mapped_frame(area.start), Some(MappedFrame::Size2MiB(_))));
+    assert!(matches!(mapped_frame(area.start + 2 * MIB), Some(MappedFrame::Size2MiB(_))));
+    for offset in (0..4 * MIB).step_by(4096) {
+        let ptr: *mut u64 = (area.start + offset).as_mut_ptr();
+        unsafe {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(offset);
+        }
+    }
+    let mapped = free_frames();
+    unsafe { vma::unmap(area.start).unwrap() };
+    assert!(mapped_frame(area.start).is_none());
+    assert_eq!(free_frames(), mapped + (4 * MIB / 4096) as usize);
+}
+
+#[test_case]
+fn areas_without_the_flag_stay_4kib() {
+    let flags = HUGE - PageTableFlags::HUGE_PAGE;
+    let area = vma::map("small", 4 * MIB, flags, VmaKind::Anonymous).unwrap();
+    assert!(matches!(mapped_frame(area.start), Some(MappedFrame::Size4KiB(_))));
+    unsafe { vma::unmap(area.start).unwrap() };
+}
+
+#[test_case]
+fn mmio_area_uses_huge_pages() {
+    let area = vma::map("mmio", 2 * MIB, HUGE, VmaKind::Mmio(PhysAddr::new(0))).unwrap();
+    assert!(matches!(mapped_frame(area.start), Some(MappedFrame::Size2MiB(_))));
+    let direct: *const u64 = memory::phys_to_virt(PhysAddr::new(0xb8000)).as_ptr();
+    let mapped: *const u64 = (area.start + 0xb8000u64).as_ptr();
+    assert_eq!(unsafe { mapped.read_volatile() }, unsafe { direct.read_volatile() });
+    let before = free_frames();
+    unsafe { vma::unmap(area.start).unwrap() };
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn large_heap_growth_uses_huge_pages() {
+    let buffer = vec![1u8; 5 * MIB as usize];
+    let huge = (HEAP_START as u64..HEAP_START as u64 + 8 * MIB)
+        .step_by(2 * MIB as usize)
+        .map(|addr| VirtAddr::new(addr).align_up(2 * MIB))
+        .any(|addr| matches!(mapped_frame(addr), Some(MappedFrame::Size2MiB(_))));
+    assert!(huge);
+    assert_eq!(buffer.iter().map(|&b| b as usize).sum::<usize>(), 5 * MIB as usize);
+}
+
+#[test_case]
+fn failed_range_mappings_are_rolled_back() {
+    use toy_os::memory::huge::{self, PageSizeKind};
+    let flags = HUGE - PageTableFlags::HUGE_PAGE;
+    let area = vma::map("rollback", 4 * 4096, flags, VmaKind::Reserved).unwrap();
+    let last = area.start + 3 * 4096u64;
+    memory::with_mapper(|mapper| huge::map_zeroed_range(mapper, last, 4096, flags, PageSizeKind::Size4KiB)).unwrap();
+    let before = free_frames();
+    let result = memory::with_mapper(|mapper| {
+        huge::map_zeroed_range(mapper, area.start, 4 * 4096, flags, PageSizeKind::Size4KiB)
+    });
+    assert!(result.is_err());
+    assert!(mapped_frame(area.start).is_none());
+    assert!(mapped_frame(area.start + 2 * 4096u64).is_none());
+    assert_eq!(free_frames(), before);
+    memory::with_mapper(|mapper| huge::unmap_range(mapper, last, 4096, true));
+    unsafe { vma::unmap(area.start).unwrap() };
+}
+
+#[test_case]
+fn huge_areas_are_not_snapshotted() {
+    use toy_os::memory::{cow, vma::VmaError};
+    let area = vma::map("huge", 2 * MIB, HUGE, VmaKind::Anonymous).unwrap();
+    let before = vma::with_kernel_areas(|areas| areas.iter().count());
+    assert!(matches!(cow::snapshot(area.start, "copy"), Err(VmaError::HugePage(start)) if start == area.start));
+    assert_eq!(vma::with_kernel_areas(|areas| areas.iter().count()), before);
+    unsafe { vma::unmap(area.start).unwrap() };
+}
@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use lazy_static::lazy_static;
+use toy_os::{allocator, exit_qemu, memory, serial_print, serial_println, QemuExitCode};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+const WRITE_CODE: u8 = 0;
+const EXECUTE_HEAP: u8 = 1;
+static STAGE: AtomicU8 = AtomicU8::new(WRITE_CODE);
+static TARGET: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+    assert!(memory::protect::enabled());
+    init_test_idt();
+
+    serial_print!("memory_protection::write_to_code...\t");
+    let code = main as *const () as *mut u8;
+    TARGET.store(code as u64, Ordering::SeqCst);
+    unsafe { code.write_volatile(0xcc) };
+
+    serial_println!("[write to code did not fault]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// runs from the first fault handler, the test never returns from there
+fn execute_heap() -> ! {
+    serial_print!("memory_protection::execute_heap_data...\t");
+    // a lone `ret` instruction
+    let code = Box::leak(Box::new([0xc3u8; 16]));
+    TARGET.store(code.as_ptr() as u64, Ordering::SeqCst);
+    STAGE.store(EXECUTE_HEAP, Ordering::SeqCst);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+
+    serial_println!("[heap data was executed]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        // no IST, so the nested fault of the second stage gets a fresh frame
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let addr = Cr2::read().as_u64();
+    let expected = match STAGE.load(Ordering::SeqCst) {
+        WRITE_CODE => PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE,
+        _ => PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::INSTRUCTION_FETCH,
+    };
+    if addr != TARGET.load(Ordering::SeqCst) || !error_code.contains(expected) {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected fault at {:#x} ({:?})", addr, error_code);
+        exit_qemu(QemuExitCode::Failed);
+        loop {}
+    }
+    serial_println!("[ok]");
+    match STAGE.load(Ordering::SeqCst) {
+        WRITE_CODE => execute_heap(),
+        _ => {
+            exit_qemu(QemuExitCode::Success);
+            loop {}
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
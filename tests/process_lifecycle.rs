@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::memory::{self, cow, vma::VmaKind, AddressSpace, GlobalFrameAllocator};
+use toy_os::process::{self, Pid};
+use toy_os::usermode::UserExit;
+use toy_os::{allocator, programs, thread};
+use x86_64::structures::paging::{
+    mapper::{MappedFrame, TranslateResult},
+    PageTableFlags, PhysFrame, Translate,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn mapping(space: &AddressSpace, addr: VirtAddr) -> (PhysFrame, PageTableFlags) {
+    space.with_table(|mapper| match mapper.translate(addr) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+        _ => panic!("{:?} is not mapped", addr),
+    })
+}
+
+#[test_case]
+fn fork_shares_pages_copy_on_write() {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE;
+    let mut parent = AddressSpace::new().unwrap();
+    let area = parent.map("data", 4096, flags, VmaKind::Anonymous).unwrap();
+    let child = parent.fork().unwrap();
+    let (frame, parent_flags) = mapping(&parent, area.start);
+    let (child_frame, child_flags) = mapping(&child, area.start);
+    assert_eq!(frame, child_frame);
+    for flags in [parent_flags, child_flags] {
+        assert!(flags.contains(cow::COW) && !flags.contains(PageTableFlags::WRITABLE));
+    }
+    assert_eq!(GlobalFrameAllocator.references(frame), 2);
+    drop(child);
+    assert_eq!(GlobalFrameAllocator.references(frame), 1);
+}
+
+#[test_case]
+fn parent_waits_for_its_exec_child() {
+    let family = programs::find("family").unwrap();
+    let pid = process::spawn("family", family, &["family"], process::ENVIRONMENT, None).unwrap();
+    // the child runs hello with one argument and exits with argc
+    assert_eq!(process::run(pid), Some(UserExit::Exit(2)));
+    assert_eq!(process::reap(pid), Some(UserExit::Exit(2)));
+    assert!(process::snapshot_processes().is_empty());
+}
+
+#[test_case]
+fn orphans_are_reaped_by_the_kernel() {
+    let family = programs::find("family").unwrap();
+    let pid = process::spawn("family", family, &["family", "orphan"], process::ENVIRONMENT, None).unwrap();
+    assert_eq!(process::run(pid), Some(UserExit::Exit(0)));
+    process::reap(pid);
+    for _ in 0..100 {
+        if process::snapshot_processes().is_empty() {
+            return;
+        }
+        thread::sleep(1);
+    }
+    panic!("the orphan was never reaped");
+}
+
+#[test_case]
+fn wait_without_children_fails() {
+    assert_eq!(process::wait(Pid::from_raw(u64::MAX - 1), None), None);
+}
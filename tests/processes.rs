@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::allocator;
+use toy_os::memory::{self, AddressSpace};
+use toy_os::process::{self, ProcessState};
+use toy_os::programs;
+use toy_os::usermode::UserExit;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::frame_stats().unwrap().free_frames
+}
+
+#[test_case]
+fn process_runs_in_its_own_address_space() {
+    let before = free_frames();
+    let hello = programs::find("hello").unwrap();
+    let parent = process::spawn("hello", hello, &["hello"], &[], None).unwrap();
+    let child = process::spawn("hello", hello, &["hello", "x"], &[], Some(parent)).unwrap();
+    assert_ne!(parent, child);
+
+    assert_eq!(process::run(child), Some(UserExit::Exit(2)));
+    assert_eq!(Cr3::read().0, memory::kernel_level_4_frame());
+    assert_eq!(process::current(), None);
+    // exited processes can't run again
+    assert_eq!(process::run(child), None);
+
+    let snapshot = process::snapshot_processes();
+    let info = snapshot.iter().find(|p| p.pid == child).unwrap();
+    assert_eq!(info.parent, Some(parent));
+    assert_eq!(info.state, ProcessState::Exited(UserExit::Exit(2)));
+    // greeting, indent + argument + newline twice, exit
+    assert_eq!(info.usage.syscalls, 8);
+    assert!(info.usage.pages > 0);
+
+    assert_eq!(process::reap(child), Some(UserExit::Exit(2)));
+    assert_eq!(process::reap(child), None);
+    // not exited yet
+    assert_eq!(process::reap(parent), None);
+    assert_eq!(process::run(parent), Some(UserExit::Exit(1)));
+    assert_eq!(process::reap(parent), Some(UserExit::Exit(1)));
+    assert!(process::snapshot_processes().is_empty());
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn user_halves_are_private_and_kernel_halves_shared() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    let mut first = AddressSpace::new().unwrap();
+    let mut second = AddressSpace::new().unwrap();
+    let area = first.map("data", 4096, flags, memory::vma::VmaKind::Anonymous).unwrap();
+    let same = second.map_at("data", area.start, 4096, flags, memory::vma::VmaKind::Anonymous).unwrap();
+    let phys = |space: &AddressSpace, addr| space.with_table(|mapper| mapper.translate_addr(addr));
+    assert_ne!(phys(&first, area.start), phys(&second, same.start));
+    // the kernel's own code translates the same everywhere
+    let code = VirtAddr::new(main as *const () as u64);
+    let kernel = memory::with_mapper(|mapper| mapper.translate_addr(code));
+    assert_eq!(phys(&first, code), kernel);
+    assert_eq!(phys(&second, code), kernel);
+}
+
+#[test_case]
+fn failed_map_at_leaves_existing_mappings_alone() {
+    use toy_os::memory::{huge::{self, PageSizeKind}, vma::{VmaError, VmaKind}, GlobalFrameAllocator};
+    use x86_64::structures::paging::PhysFrame;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    let mut space = AddressSpace::new().unwrap();
+    let window = space.map("window", 3 * 4096, flags, VmaKind::Reserved).unwrap();
+    let taken = window.start + 2 * 4096u64;
+    space.with_table(|mapper| huge::map_zeroed_range(mapper, taken, 4096, flags, PageSizeKind::Size4KiB)).unwrap();
+    // the reserved area doesn't own the page, it stays mapped but untracked
+    space.unmap(window.start).unwrap();
+    let phys = |space: &AddressSpace, addr| space.with_table(|mapper| mapper.translate_addr(addr));
+    let frame: PhysFrame = PhysFrame::containing_address(phys(&space, taken).unwrap());
+
+    let clash = space.map_at("clash", window.start, 3 * 4096, flags, VmaKind::Anonymous);
+    assert!(matches!(clash, Err(VmaError::Map(_))));
+    assert_eq!(space.areas().filter(|area| area.start == window.start).count(), 0);
+    assert_eq!(phys(&space, window.start), None);
+    assert_eq!(phys(&space, taken), Some(frame.start_address()));
+    assert_eq!(GlobalFrameAllocator.references(frame), 1);
+    space.with_table(|mapper| huge::unmap_range(mapper, taken, 4096, true));
+}
+
+#[test_case]
+fn walker_follows_the_active_address_space() {
+    use toy_os::memory::{address_space, walker};
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    let mut space = AddressSpace::new().unwrap();
+    let area = space.map("data", 4096, flags, memory::vma::VmaKind::Anonymous).unwrap();
+    let reported = || {
+        let mut found = false;
+        walker::for_each_mapping(|range| found |= range.start <= area.start && area.start < range.start + range.size);
+        found
+    };
+    assert!(!reported());
+    space.activate();
+    let seen = reported();
+    address_space::activate_kernel();
+    assert!(seen);
+}
+
+#[test_case]
+fn children_share_the_keyboard_of_their_parent() {
+    use toy_os::task::keyboard;
+    let hello = programs::find("hello").unwrap();
+    let parent = process::spawn("hello", hello, &["hello"], &[], None).unwrap();
+    let child = process::spawn("hello", hello, &["hello"], &[], Some(parent)).unwrap();
+    assert!(process::descends_from(child, parent) && process::descends_from(parent, parent));
+    assert!(!process::descends_from(parent, child));
+    keyboard::set_foreground(Some(parent));
+    assert_eq!(keyboard::foreground(), Some(parent));
+    keyboard::set_foreground(None);
+    assert_eq!(keyboard::foreground(), None);
+    for pid in [child, parent] {
+        process::run(pid).unwrap();
+        process::reap(pid).unwrap();
+    }
+}
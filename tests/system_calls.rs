@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::Ordering;
+use toy_os::interrupts::TICK_COUNTER;
+use toy_os::{allocator, memory, thread};
+use toy_os::syscall::{Syscall, SyscallError};
+use toy_os::usermode::{self, UserExit};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+const SYSCALL: [u8; 2] = [0x0f, 0x05];
+const INT_0X80: [u8; 2] = [0xcd, 0x80];
+
+// mov eax, number; <entry>; mov rdi, rax; mov eax, exit; <entry>
+// so the program exits with whatever the call returned
+fn call_and_exit(number: u64, entry: [u8; 2], setup: &[u8]) -> UserExit {
+    let mut program = [0u8; 64];
+    let mut len = 0;
+    let mut push = |bytes: &[u8]| {
+        program[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+    };
+    push(setup);
+    push(&[0xb8]);
+    push(&(number as u32).to_le_bytes());
+    push(&entry);
+    push(&[0x48, 0x89, 0xc7]);
+    push(&[0xb8]);
+    push(&(Syscall::Exit as u32).to_le_bytes());
+    push(&entry);
+    usermode::run(&program[..len]).unwrap()
+}
+
+#[test_case]
+fn demo_program_exits_through_syscall() {
+    assert_eq!(usermode::run(&usermode::DEMO_PROGRAM).unwrap(), UserExit::Exit(55));
+}
+
+#[test_case]
+fn ticks_through_both_entries() {
+    let before = TICK_COUNTER.load(Ordering::Relaxed) as u64;
+    for entry in [SYSCALL, INT_0X80] {
+        match call_and_exit(Syscall::Ticks as u64, entry, &[]) {
+            UserExit::Exit(ticks) => assert!(ticks >= before),
+            exit => panic!("unexpected exit {:?}", exit),
+        }
+    }
+}
+
+#[test_case]
+fn sleep_waits_for_ticks() {
+    let before = TICK_COUNTER.load(Ordering::Relaxed);
+    // mov edi, 3
+    assert_eq!(call_and_exit(Syscall::Sleep as u64, SYSCALL, &[0xbf, 3, 0, 0, 0]), UserExit::Exit(0));
+    assert!(TICK_COUNTER.load(Ordering::Relaxed) >= before + 3);
+}
+
+#[test_case]
+fn write_prints_user_memory() {
+    // lea rdi, [rip + 22]; mov esi, 5; mov eax, write; syscall;
+    // mov rdi, rax; mov eax, exit; syscall; "hello"
+    let program = [
+        0x48, 0x8d, 0x3d, 0x16, 0x00, 0x00, 0x00,
+        0xbe, 0x05, 0x00, 0x00, 0x00,
+        0xb8, 0x00, 0x00, 0x00, 0x00,
+        0x0f, 0x05,
+        0x48, 0x89, 0xc7,
+        0xb8, 0x03, 0x00, 0x00, 0x00,
+        0x0f, 0x05,
+        b'h', b'e', b'l', b'l', b'o',
+    ];
+    assert_eq!(usermode::run(&program).unwrap(), UserExit::Exit(5));
+}
+
+#[test_case]
+fn write_rejects_kernel_memory() {
+    static SECRET: [u8; 4] = *b"key!";
+    let mut setup = [0u8; 15];
+    // mov rdi, &SECRET; mov esi, 4
+    setup[..2].copy_from_slice(&[0x48, 0xbf]);
+    setup[2..10].copy_from_slice(&(SECRET.as_ptr() as u64).to_le_bytes());
+    setup[10..].copy_from_slice(&[0xbe, 4, 0, 0, 0]);
+    let exit = call_and_exit(Syscall::Write as u64, SYSCALL, &setup);
+    assert_eq!(exit, UserExit::Exit(SyscallError::BadAddress.as_u64()));
+}
+
+#[test_case]
+fn unknown_calls_fail() {
+    let exit = call_and_exit(99, INT_0X80, &[]);
+    assert_eq!(exit, UserExit::Exit(SyscallError::UnknownCall.as_u64()));
+}
@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use futures_util::future::{select, Either};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use toy_os::task::{self, executor::{self, Executor, KillRequestResult, TaskState}};
+use toy_os::interrupts::TICK_COUNTER;
+use toy_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+// never finishes
+struct Forever;
+impl Future for Forever {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, _context: &mut Context) -> Poll<()> {
+        Poll::Pending
+    }
+}
+
+fn ticks() -> u64 {
+    TICK_COUNTER.load(Ordering::Relaxed) as u64
+}
+
+fn state_of(id: u64) -> Option<TaskState> {
+    executor::snapshot_tasks().into_iter().find(|task| task.id == id).map(|task| task.state)
+}
+
+#[test_case]
+fn tasks_can_select_on_cancellation() {
+    static OUTCOME: Mutex<Option<&'static str>> = Mutex::new(None);
+    static CLEANED_UP: AtomicBool = AtomicBool::new(false);
+    let mut executor = Executor::new();
+    let handle = executor.spawner().spawn(async {
+        let token = task::current_token().unwrap();
+        token.on_cancel(|| CLEANED_UP.store(true, Ordering::Relaxed));
+        let outcome = match select(Forever, token.cancelled()).await {
+            Either::Left(_) => "finished",
+            Either::Right(_) => "cancelled",
+        };
+        *OUTCOME.lock() = Some(outcome);
+    }).unwrap();
+    executor.run_until_idle();
+    assert_eq!(state_of(handle.id()), Some(TaskState::Waiting));
+    assert_eq!(executor::request_kill(handle.id()), KillRequestResult::Queued);
+    assert_eq!(executor::request_kill(handle.id()), KillRequestResult::AlreadyQueued);
+    executor.run_until_idle();
+    assert_eq!(*OUTCOME.lock(), Some("cancelled"));
+    assert!(CLEANED_UP.load(Ordering::Relaxed));
+    assert_eq!(state_of(handle.id()), None);
+}
+
+#[test_case]
+fn tasks_ignoring_cancellation_are_dropped_after_the_grace_period() {
+    static CLEANED_UP_AT: AtomicU64 = AtomicU64::new(0);
+    let mut executor = Executor::new();
+    let handle = executor.spawner().spawn(async {
+        task::current_token().unwrap().on_cancel(|| CLEANED_UP_AT.store(ticks(), Ordering::Relaxed));
+        Forever.await
+    }).unwrap();
+    executor.run_until_idle();
+    let killed_at = ticks();
+    assert_eq!(executor::request_kill(handle.id()), KillRequestResult::Queued);
+    executor.run_until_idle();
+    assert_eq!(state_of(handle.id()), Some(TaskState::KillRequested));
+    while state_of(handle.id()).is_some() {
+        x86_64::instructions::hlt();
+        executor.run_until_idle();
+    }
+    assert!(handle.is_finished());
+    assert!(CLEANED_UP_AT.load(Ordering::Relaxed) >= killed_at + executor::GRACE_TICKS);
+}
+
+#[test_case]
+fn forced_kills_drop_at_once_without_cleanup() {
+    static CLEANED_UP: AtomicBool = AtomicBool::new(false);
+    let mut executor = Executor::new();
+    let handle = executor.spawner().spawn(async {
+        task::current_token().unwrap().on_cancel(|| CLEANED_UP.store(true, Ordering::Relaxed));
+        Forever.await
+    }).unwrap();
+    executor.run_until_idle();
+    assert_eq!(executor::request_kill(handle.id()), KillRequestResult::Queued);
+    executor.run_until_idle();
+    assert_eq!(executor::force_kill(handle.id()), KillRequestResult::Queued);
+    assert_eq!(executor::force_kill(handle.id()), KillRequestResult::AlreadyQueued);
+    executor.run_until_idle();
+    assert_eq!(state_of(handle.id()), None);
+    assert!(handle.is_finished());
+    assert!(!CLEANED_UP.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn kills_wait_for_the_executor_owning_the_task() {
+    let mut owner = Executor::new();
+    let handle = owner.spawner().spawn(Forever).unwrap();
+    owner.run_until_idle();
+    assert_eq!(executor::force_kill(handle.id()), KillRequestResult::Queued);
+    // another executor draining the requests leaves the task alone
+    Executor::new().run_until_idle();
+    assert_eq!(state_of(handle.id()), Some(TaskState::KillRequested));
+    assert!(!handle.is_finished());
+    owner.run_until_idle();
+    assert_eq!(state_of(handle.id()), None);
+    assert!(handle.is_finished());
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use toy_os::task::{self, executor::{self, Executor}, Cancelled, Priority, Task};
+use toy_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+static RESULTS: Mutex<Vec<Result<u64, Cancelled>>> = Mutex::new(Vec::new());
+
+// never finishes
+struct Forever;
+impl Future for Forever {
+    type Output = u64;
+    fn poll(self: Pin<&mut Self>, _context: &mut Context) -> Poll<u64> {
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn handles_resolve_to_the_output() {
+    RESULTS.lock().clear();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let handles: Vec<_> = (1..=3).map(|n| task::spawn(async move { n * n }).unwrap()).collect();
+        for handle in handles {
+            let result = handle.await;
+            RESULTS.lock().push(result);
+        }
+    })).unwrap();
+    executor.run_until_idle();
+    assert_eq!(*RESULTS.lock(), [Ok(1), Ok(4), Ok(9)]);
+}
+
+#[test_case]
+fn handles_report_finished_tasks() {
+    let mut executor = Executor::new();
+    let handle = executor.spawner().spawn_named("answer", Priority::High, async { 42 }).unwrap();
+    assert!(!handle.is_finished());
+    executor.run_until_idle();
+    assert!(handle.is_finished());
+}
+
+#[test_case]
+fn killed_tasks_resolve_to_cancelled() {
+    RESULTS.lock().clear();
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let stuck = spawner.spawn(Forever).unwrap();
+    let stuck_id = stuck.id();
+    spawner.spawn(async move {
+        let result = stuck.await;
+        RESULTS.lock().push(result);
+    }).unwrap();
+    executor.run_until_idle();
+    assert!(RESULTS.lock().is_empty());
+    executor::force_kill(stuck_id);
+    executor.run_until_idle();
+    assert_eq!(*RESULTS.lock(), [Err(Cancelled)]);
+    assert!(executor::snapshot_tasks().is_empty());
+}
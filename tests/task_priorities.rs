@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use spin::Mutex;
+use toy_os::task::{executor::{self, Executor}, Priority, Task};
+use toy_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+static ORDER: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+fn record(name: &'static str) -> Task {
+    Task::new_named(name, Priority::Normal, async move { ORDER.lock().push(name) })
+}
+
+// pending once, waking itself right away
+struct YieldNow(bool);
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn higher_classes_run_first() {
+    ORDER.lock().clear();
+    let mut executor = Executor::new();
+    for (name, priority) in [("low", Priority::Low), ("normal", Priority::Normal), ("high", Priority::High)] {
+        executor.spawn(Task::new_named(name, priority, async move { ORDER.lock().push(name) })).unwrap();
+    }
+    executor.run_until_idle();
+    assert_eq!(*ORDER.lock(), ["high", "normal", "low"]);
+}
+
+#[test_case]
+fn waiting_tasks_age_past_busy_ones() {
+    static HIGH_ROUNDS: AtomicU64 = AtomicU64::new(0);
+    static LOW_RAN_AT: AtomicU64 = AtomicU64::new(u64::MAX);
+    let mut executor = Executor::new();
+    executor.spawn(Task::new_named("busy", Priority::High, async {
+        for _ in 0..50 {
+            HIGH_ROUNDS.fetch_add(1, Ordering::Relaxed);
+            YieldNow(false).await;
+        }
+    })).unwrap();
+    executor.spawn(Task::new_named("starving", Priority::Low, async {
+        LOW_RAN_AT.store(HIGH_ROUNDS.load(Ordering::Relaxed), Ordering::Relaxed);
+    })).unwrap();
+    executor.run_until_idle();
+    assert_eq!(HIGH_ROUNDS.load(Ordering::Relaxed), 50);
+    assert!(LOW_RAN_AT.load(Ordering::Relaxed) < 50);
+}
+
+#[test_case]
+fn priority_changes_apply_to_queued_tasks() {
+    ORDER.lock().clear();
+    let mut executor = Executor::new();
+    executor.spawn(record("early")).unwrap();
+    executor.spawn(record("late")).unwrap();
+    let late = executor::snapshot_tasks().into_iter().find(|task| task.name == "late").unwrap();
+    assert_eq!(late.priority, Priority::Normal);
+    assert!(executor::set_priority(late.id, Priority::High));
+    executor.run_until_idle();
+    assert_eq!(*ORDER.lock(), ["late", "early"]);
+    assert!(!executor::set_priority(late.id, Priority::Low));
+}
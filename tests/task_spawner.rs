@@ -0,0 +1,126 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use toy_os::task::{self, executor::{self, Executor, KillRequestResult}, Priority, Task};
+use toy_os::{allocator, memory};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+static ORDER: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+async fn record(name: &'static str) {
+    ORDER.lock().push(name)
+}
+
+#[test_case]
+fn spawn_needs_a_running_executor() {
+    assert!(task::spawn(record("orphan")).is_none());
+}
+
+#[test_case]
+fn running_tasks_spawn_more_tasks() {
+    ORDER.lock().clear();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        ORDER.lock().push("parent");
+        task::spawn(async {
+            ORDER.lock().push("child");
+            task::spawn(record("grandchild")).unwrap();
+        })
+        .unwrap();
+    })).unwrap();
+    executor.run_until_idle();
+    assert_eq!(*ORDER.lock(), ["parent", "child", "grandchild"]);
+    assert!(executor::snapshot_tasks().is_empty());
+}
+
+#[test_case]
+fn spawners_queue_before_the_executor_runs() {
+    ORDER.lock().clear();
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    spawner.clone().spawn_named("first", Priority::High, record("first")).unwrap();
+    let second = spawner.spawn_named("second", Priority::Low, record("second")).unwrap();
+    let queued = executor::snapshot_tasks().into_iter().find(|task| task.id == second.id()).unwrap();
+    assert_eq!(queued.priority, Priority::Low);
+    executor.run_until_idle();
+    assert_eq!(*ORDER.lock(), ["first", "second"]);
+}
+
+#[test_case]
+fn killed_spawned_tasks_never_run() {
+    ORDER.lock().clear();
+    let mut executor = Executor::new();
+    let handle = executor.spawner().spawn(record("killed")).unwrap();
+    assert_eq!(executor::request_kill(handle.id()), KillRequestResult::Queued);
+    executor.run_until_idle();
+    assert!(ORDER.lock().is_empty());
+    assert!(executor::snapshot_tasks().is_empty());
+}
+
+#[test_case]
+fn spawn_stops_when_the_executor_is_done() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        assert!(task::spawn(async {}).is_some());
+    })).unwrap();
+    executor.run_until_idle();
+    assert!(task::spawn(record("after idle")).is_none());
+    drop(executor);
+    assert!(task::spawn(record("after drop")).is_none());
+}
+
+#[test_case]
+fn nested_executors_hand_spawn_back() {
+    ORDER.lock().clear();
+    let mut outer = Executor::new();
+    outer.spawn(Task::new(async {
+        let mut inner = Executor::new();
+        inner.spawn(Task::new(record("inner"))).unwrap();
+        inner.run_until_idle();
+        drop(inner);
+        task::spawn(record("outer")).unwrap();
+    })).unwrap();
+    outer.run_until_idle();
+    drop(outer);
+    assert_eq!(*ORDER.lock(), ["inner", "outer"]);
+    assert!(task::spawn(record("after drop")).is_none());
+}
+
+#[test_case]
+fn spawners_outliving_their_executor_spawn_nothing() {
+    let executor = Executor::new();
+    let spawner = executor.spawner();
+    let queued = spawner.spawn(record("queued")).unwrap();
+    drop(executor);
+    assert!(spawner.clone().spawn(record("late")).is_none());
+    assert!(executor::snapshot_tasks().iter().all(|task| task.id != queued.id()));
+}
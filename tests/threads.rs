@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use toy_os::interrupts::TICK_COUNTER;
+use toy_os::usermode::UserExit;
+use toy_os::{allocator, memory, process, programs, thread};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn ticks() -> u64 {
+    TICK_COUNTER.load(Ordering::Relaxed) as u64
+}
+
+#[test_case]
+fn spawned_threads_run_and_join() {
+    static SUM: AtomicU64 = AtomicU64::new(0);
+    let ids: alloc::vec::Vec<_> = (1..=3)
+        .map(|n| thread::spawn("adder", move || {
+            SUM.fetch_add(n, Ordering::Relaxed);
+        }).unwrap())
+        .collect();
+    for id in ids {
+        thread::join(id);
+    }
+    assert_eq!(SUM.load(Ordering::Relaxed), 6);
+}
+
+#[test_case]
+fn timer_preempts_busy_threads() {
+    static ROUNDS: AtomicU64 = AtomicU64::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let busy = thread::spawn("busy", || {
+        while !STOP.load(Ordering::Relaxed) {
+            ROUNDS.fetch_add(1, Ordering::Relaxed);
+        }
+    })
+    .unwrap();
+    // neither side yields, so only the timer lets the other one run
+    let until = ticks() + 5;
+    while ticks() < until {}
+    assert!(ROUNDS.load(Ordering::Relaxed) > 0);
+    STOP.store(true, Ordering::Relaxed);
+    thread::join(busy);
+}
+
+#[test_case]
+fn sleeping_thread_wakes_up() {
+    static SLEPT: AtomicU64 = AtomicU64::new(0);
+    let sleeper = thread::spawn("sleeper", || {
+        let start = ticks();
+        thread::sleep(3);
+        SLEPT.store(ticks() - start, Ordering::Relaxed);
+    })
+    .unwrap();
+    thread::join(sleeper);
+    assert!(SLEPT.load(Ordering::Relaxed) >= 3);
+}
+
+#[test_case]
+fn user_programs_run_on_threads() {
+    static EXIT: AtomicU64 = AtomicU64::new(0);
+    let runner = thread::spawn("runner", || {
+        let pid = process::spawn("hello", programs::find("hello").unwrap(), &["hello"], &[], None).unwrap();
+        process::run(pid);
+        if let Some(UserExit::Exit(value)) = process::reap(pid) {
+            EXIT.store(value, Ordering::Relaxed);
+        }
+    })
+    .unwrap();
+    thread::join(runner);
+    assert_eq!(EXIT.load(Ordering::Relaxed), 1);
+    assert_eq!(thread::current().as_u64(), 0);
+}
+
+#[test_case]
+fn joined_threads_free_their_stacks() {
+    let before = memory::frame_stats().unwrap().free_frames;
+    let id = thread::spawn("short", || {}).unwrap();
+    thread::join(id);
+    assert_eq!(memory::frame_stats().unwrap().free_frames, before);
+    assert!(thread::snapshot_threads().iter().all(|t| t.id != id));
+}
@@ -0,0 +1,66 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::memory;
+use toy_os::usermode::{self, UserExit};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+    toy_os::gdt::protect_stacks();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+static KERNEL_BYTE: u8 = 0;
+
+fn free_frames() -> usize {
+    memory::frame_stats().unwrap().free_frames
+}
+
+#[test_case]
+fn demo_program_traps_back_with_its_result() {
+    let before = free_frames();
+    assert_eq!(usermode::run(&usermode::DEMO_PROGRAM).unwrap(), UserExit::Exit(55));
+    // the address space goes away with all of its page tables
+    assert_eq!(free_frames(), before);
+    assert_eq!(usermode::run(&usermode::DEMO_PROGRAM).unwrap(), UserExit::Exit(55));
+}
+
+#[test_case]
+fn kernel_memory_is_not_user_accessible() {
+    let addr = &KERNEL_BYTE as *const u8 as u64;
+    let mut program = [0u8; 13];
+    // mov rax, addr; mov byte [rax], 1
+    program[..2].copy_from_slice(&[0x48, 0xb8]);
+    program[2..10].copy_from_slice(&addr.to_le_bytes());
+    program[10..].copy_from_slice(&[0xc6, 0x00, 0x01]);
+    assert_eq!(usermode::run(&program).unwrap(), UserExit::PageFault(VirtAddr::new(addr)));
+    assert_eq!(KERNEL_BYTE, 0);
+}
+
+#[test_case]
+fn privileged_instructions_fault() {
+    // cli
+    assert_eq!(usermode::run(&[0xfa]).unwrap(), UserExit::GeneralProtection);
+    // ud2
+    assert_eq!(usermode::run(&[0x0f, 0x0b]).unwrap(), UserExit::InvalidOpcode);
+}
@@ -0,0 +1,228 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::memory::{self, guard, vma::{self, VmaError, VmaKind}, GlobalFrameAllocator};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PageTableFlags, PhysFrame, Size2MiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset);
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::with_mapper(|mapper| mapper.translate_addr(addr).is_some())
+}
+
+#[test_case]
+fn anonymous_area_is_zeroed_and_freed() {
+    let before = memory::frame_stats().unwrap().free_frames;
+    let area = vma::map("test", 3 * 4096, FLAGS, VmaKind::Anonymous).unwrap();
+    assert_eq!(area.size(), 3 * 4096);
+    // at least the three data frames, plus any page tables created for them
+    let mapped = memory::frame_stats().unwrap().free_frames;
+    assert!(mapped <= before - 3);
+    let words: *mut u64 = area.start.as_mut_ptr();
+    for index in 0..3 * 512 {
+        unsafe {
+            assert_eq!(words.add(index).read_volatile(), 0);
+            words.add(index).write_volatile(index as u64);
+        }
+    }
+    unsafe { vma::unmap(area.start).unwrap() };
+    assert!(!is_mapped(area.start));
+    assert!(vma::find(area.start).is_none());
+    assert_eq!(memory::frame_stats().unwrap().free_frames, mapped + 3);
+}
+
+#[test_case]
+fn areas_do_not_overlap() {
+    let first = vma::map("first", 4096, FLAGS, VmaKind::Reserved).unwrap();
+    let second = vma::map("second", 2 * 4096, FLAGS, VmaKind::Reserved).unwrap();
+    assert!(second.start >= first.end || second.end <= first.start);
+    let clash = vma::map_at("clash", second.start + 4096u64, 4096, FLAGS, VmaKind::Reserved);
+    assert!(matches!(clash, Err(VmaError::Overlap(area)) if area.start == second.start));
+    unsafe {
+        vma::unmap(first.start).unwrap();
+        vma::unmap(second.start).unwrap();
+    }
+    // freed ranges are handed out again
+    let again = vma::map("again", 4096, FLAGS, VmaKind::Reserved).unwrap();
+    assert_eq!(again.start, first.start.min(second.start));
+    unsafe { vma::unmap(again.start).unwrap() };
+}
+
+#[test_case]
+fn mmio_area_maps_the_device_frames() {
+    let area = vma::map("vga", 4096, FLAGS, VmaKind::Mmio(PhysAddr::new(0xb8000))).unwrap();
+    let direct: *const u16 = memory::phys_to_virt(PhysAddr::new(0xb8000)).as_ptr();
+    let mapped: *const u16 = area.start.as_ptr();
+    assert_eq!(unsafe { mapped.read_volatile() }, unsafe { direct.read_volatile() });
+    let before = memory::frame_stats().unwrap().free_frames;
+    unsafe { vma::unmap(area.start).unwrap() };
+    // device frames aren't owned by the area
+    assert_eq!(memory::frame_stats().unwrap().free_frames, before);
+}
+
+#[test_case]
+fn stacks_get_a_guard_page() {
+    let stack = guard::alloc_stack("test stack", 4).unwrap();
+    assert_eq!(stack.top - stack.bottom, 4 * 4096);
+    assert!(!is_mapped(stack.guard));
+    assert!(is_mapped(stack.bottom));
+    assert_eq!(guard::find(stack.guard).unwrap().name, "test stack");
+    let area = vma::find(stack.bottom).unwrap();
+    assert_eq!(area.kind, VmaKind::Stack);
+    unsafe { guard::free_stack(stack) };
+    assert!(guard::find(area.start).is_none());
+}
+
+#[test_case]
+fn walker_reports_merged_ranges() {
+    use toy_os::memory::walker;
+    let area = vma::map("walked", 4 * 4096, FLAGS, VmaKind::Mmio(PhysAddr::new(0xb8000))).unwrap();
+    let mut found = None;
+    walker::for_each_mapping(|range| {
+        if range.start <= area.start && area.start < range.start + range.size {
+            found = Some(*range);
+        }
+    });
+    let range = found.expect("mapping not reported");
+    assert_eq!(range.start, area.start);
+    assert_eq!(range.size, 4 * 4096);
+    assert_eq!(range.phys, PhysAddr::new(0xb8000));
+    assert_eq!(range.permissions(), "rw--");
+    assert_eq!(range.marker(), "nc");
+    unsafe { vma::unmap(area.start).unwrap() };
+}
+
+#[test_case]
+fn memory_map_matches_frame_allocator() {
+    use toy_os::memory::regions::{self, RegionClass};
+    let stats = memory::frame_stats().unwrap();
+    let usable = memory::regions()
+        .iter()
+        .filter(|region| regions::region_class(region) == RegionClass::Usable);
+    let used: usize = usable.clone().map(regions::used_frames_in).sum();
+    assert_eq!(used, stats.used_frames);
+    assert_eq!(regions::region_totals().usable, stats.total_bytes() as u64);
+    assert!(regions::region_totals().kernel > 0);
+    assert!(usable.count() > 0);
+}
+
+fn free_frames() -> usize {
+    memory::frame_stats().unwrap().free_frames
+}
+
+#[test_case]
+fn freed_frames_are_reused() {
+    let mut frames = GlobalFrameAllocator;
+    let before = free_frames();
+    let frame: PhysFrame = frames.allocate_frame().unwrap();
+    assert_eq!(frames.references(frame), 1);
+    assert_eq!(free_frames(), before - 1);
+    unsafe { frames.deallocate_frame(frame) };
+    assert_eq!(frames.references(frame), 0);
+    assert_eq!(free_frames(), before);
+    let again: PhysFrame = frames.allocate_frame().unwrap();
+    assert_eq!(again, frame);
+    unsafe { frames.deallocate_frame(again) };
+}
+
+#[test_case]
+fn contiguous_runs_are_allocated_and_freed() {
+    let mut frames = GlobalFrameAllocator;
+    let before = free_frames();
+    let start = frames.allocate_contiguous(16).unwrap();
+    assert_eq!(free_frames(), before - 16);
+    for frame in PhysFrame::range(start, start + 16) {
+        assert_eq!(frames.references(frame), 1);
+    }
+    unsafe { frames.deallocate_contiguous(start, 16) };
+    assert_eq!(free_frames(), before);
+    assert!(PhysFrame::range(start, start + 16).all(|frame| frames.references(frame) == 0));
+}
+
+#[test_case]
+fn shared_huge_frames_are_freed_whole() {
+    let mut frames = GlobalFrameAllocator;
+    let before = free_frames();
+    let frame: PhysFrame<Size2MiB> = frames.allocate_frame().unwrap();
+    frames.share(frame);
+    assert_eq!(frames.references(frame), 2);
+    let last: PhysFrame = PhysFrame::containing_address(frame.start_address() + (Size2MiB::SIZE - 4096));
+    assert_eq!(frames.references(last), 2);
+    unsafe { frames.deallocate_frame(frame) };
+    assert_eq!(frames.references(frame), 1);
+    assert_eq!(frames.references(last), 1);
+    assert_eq!(free_frames(), before - 512);
+    unsafe { frames.deallocate_frame(frame) };
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn huge_frames_are_aligned() {
+    let mut frames = GlobalFrameAllocator;
+    let before = free_frames();
+    let frame: PhysFrame<Size2MiB> = frames.allocate_frame().unwrap();
+    assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+    assert_eq!(free_frames(), before - 512);
+    unsafe { frames.deallocate_frame(frame) };
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn failed_map_at_leaves_existing_mappings_alone() {
+    use toy_os::memory::huge::{self, PageSizeKind};
+    let window = vma::map("window", 3 * 4096, FLAGS, VmaKind::Reserved).unwrap();
+    let taken = window.start + 2 * 4096u64;
+    memory::with_mapper(|mapper| huge::map_zeroed_range(mapper, taken, 4096, FLAGS, PageSizeKind::Size4KiB)).unwrap();
+    // the reserved area doesn't own the page, it stays mapped but untracked
+    unsafe { vma::unmap(window.start).unwrap() };
+    let frame: PhysFrame = PhysFrame::containing_address(memory::with_mapper(|mapper| mapper.translate_addr(taken)).unwrap());
+
+    let clash = vma::map_at("clash", window.start, 3 * 4096, FLAGS, VmaKind::Anonymous);
+    assert!(matches!(clash, Err(VmaError::Map(_))));
+    assert!(vma::find(window.start).is_none());
+    assert!(!is_mapped(window.start) && !is_mapped(window.start + 4096u64));
+    assert_eq!(memory::with_mapper(|mapper| mapper.translate_addr(taken)), Some(frame.start_address()));
+    assert_eq!(GlobalFrameAllocator.references(frame), 1);
+    memory::with_mapper(|mapper| huge::unmap_range(mapper, taken, 4096, true));
+}
+
+#[test_case]
+fn kernel_code_is_read_only_in_the_physical_mapping() {
+    use x86_64::structures::paging::mapper::TranslateResult;
+    let code = VirtAddr::from_ptr(main as *const ());
+    let frame = memory::with_mapper(|mapper| mapper.translate_addr(code)).unwrap();
+    let alias = memory::phys_to_virt(frame);
+    let flags = memory::with_mapper(|mapper| match mapper.translate(alias) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("physical memory mapping is missing {:?}", frame),
+    });
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+}